@@ -0,0 +1,65 @@
+/*
+    ファイルから読み込む場合と、メモリにマップして読み込む場合のページの読み込み速度を比べる。
+    cargo bench --bench disk で実行する。
+*/
+use std::time::{Duration, Instant};
+
+use artsdb::disk::{DiskManager, DiskOptions, PageId};
+use tempfile::tempdir;
+
+const PAGES: u64 = 4096;
+const READS: u64 = 200_000;
+
+fn bench_reads(name: &str, disk: &DiskManager, page_ids: &[PageId]) -> Duration {
+    let mut buf = vec![0u8; disk.data_size()];
+    // ページの読み込み順を毎回同じにするための簡単な疑似乱数
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let start = Instant::now();
+    for _ in 0..READS {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let page_id = page_ids[(state % page_ids.len() as u64) as usize];
+        disk.read_page_data(page_id, &mut buf).unwrap();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:>6}: {} random page reads in {:?} ({:.0} ns/page)",
+        name,
+        READS,
+        elapsed,
+        elapsed.as_nanos() as f64 / READS as f64
+    );
+    elapsed
+}
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("bench.db");
+    let mut disk = DiskManager::open(&path).unwrap();
+    let page_ids: Vec<_> = (0..PAGES)
+        .map(|i| {
+            let page_id = disk.allocate_page().unwrap();
+            disk.write_page_data(page_id, &vec![i as u8; disk.data_size()])
+                .unwrap();
+            page_id
+        })
+        .collect();
+    disk.sync().unwrap();
+    drop(disk);
+
+    let disk = DiskManager::open(&path).unwrap();
+    let file = bench_reads("file", &disk, &page_ids);
+    drop(disk);
+
+    let options = DiskOptions {
+        mmap: true,
+        ..Default::default()
+    };
+    let disk = DiskManager::open_with(&path, options).unwrap();
+    let mmap = bench_reads("mmap", &disk, &page_ids);
+    println!(
+        "mmap is {:.2}x the speed of file reads",
+        file.as_secs_f64() / mmap.as_secs_f64()
+    );
+}
@@ -0,0 +1,119 @@
+/*
+    ページへのアクセス列を再生して、追い出しの方針ごとのヒット率を比べる。
+    cargo bench --bench eviction で実行する。
+*/
+use std::collections::HashMap;
+
+use artsdb::buffer::{BufferId, BufferPool, Policy};
+use artsdb::disk::PageId;
+
+const POOL_SIZE: usize = 256;
+const ACCESSES: usize = 200_000;
+
+// アクセスするページIDの列を作る関数
+type Trace = fn(&mut XorShift) -> Vec<u64>;
+
+// アクセス列を毎回同じにするための簡単な疑似乱数
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+// 1割のページに8割のアクセスが集まる
+fn hot_set(rng: &mut XorShift) -> Vec<u64> {
+    let pages = POOL_SIZE as u64 * 8;
+    let hot = pages / 10;
+    (0..ACCESSES)
+        .map(|_| {
+            if rng.below(10) < 8 {
+                rng.below(hot)
+            } else {
+                hot + rng.below(pages - hot)
+            }
+        })
+        .collect()
+}
+
+// よく使うページへのアクセスの合間に、バッファより大きな範囲の全件スキャンが入る
+fn scan_with_hot_set(rng: &mut XorShift) -> Vec<u64> {
+    let hot = POOL_SIZE as u64 / 2;
+    let scan = POOL_SIZE as u64 * 4;
+    let mut trace = Vec::with_capacity(ACCESSES);
+    let mut next_scan = 0;
+    while trace.len() < ACCESSES {
+        for _ in 0..2000 {
+            trace.push(rng.below(hot));
+        }
+        trace.extend((0..scan).map(|i| hot + (next_scan + i) % (scan * 4)));
+        next_scan += scan;
+    }
+    trace.truncate(ACCESSES);
+    trace
+}
+
+// バッファより少しだけ大きい範囲を繰り返し読む
+fn looping(_: &mut XorShift) -> Vec<u64> {
+    let pages = POOL_SIZE as u64 * 5 / 4;
+    (0..ACCESSES as u64).map(|i| i % pages).collect()
+}
+
+fn hit_ratio(policy: Policy, trace: &[u64]) -> f64 {
+    let mut pool = BufferPool::new_with(POOL_SIZE, policy);
+    let mut page_table: HashMap<u64, BufferId> = HashMap::new();
+    let mut buffer_pages: Vec<Option<u64>> = vec![None; POOL_SIZE];
+    let mut hits = 0;
+    for &page in trace {
+        if let Some(&buffer_id) = page_table.get(&page) {
+            pool.record_hit(buffer_id);
+            hits += 1;
+            continue;
+        }
+        let buffer_id = pool.evict().unwrap();
+        if let Some(evicted) = buffer_pages[buffer_id.0].replace(page) {
+            page_table.remove(&evicted);
+        }
+        pool.record_load(buffer_id, PageId(page));
+        page_table.insert(page, buffer_id);
+    }
+    hits as f64 / trace.len() as f64
+}
+
+fn main() {
+    let policies = [
+        Policy::ClockSweep,
+        Policy::Lru,
+        Policy::LruK(2),
+        Policy::TwoQueue,
+        Policy::AdaptiveReplacement,
+    ];
+    let traces: [(&str, Trace); 3] = [
+        ("hot set", hot_set),
+        ("scan + hot set", scan_with_hot_set),
+        ("loop", looping),
+    ];
+    println!(
+        "pool size {} pages, {} accesses per trace",
+        POOL_SIZE, ACCESSES
+    );
+    for (name, generate) in traces {
+        let trace = generate(&mut XorShift(0x2545_f491_4f6c_dd1d));
+        println!("{}:", name);
+        for policy in policies {
+            println!(
+                "  {:>22}: {:6.2}%",
+                format!("{:?}", policy),
+                hit_ratio(policy, &trace) * 100.0
+            );
+        }
+    }
+}
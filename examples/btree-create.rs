@@ -12,6 +12,7 @@ fn main() -> Result<()> {
 
     println!("buffer initialized");
     let btree = BTree::create(&mut bufmgr)?;
+    bufmgr.set_catalog_root(btree.meta_page_id)?;
     println!("btree initialized");
 
     btree.insert(&mut bufmgr, b"Kanagawa", b"Yokohama")?;
@@ -1,17 +1,20 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 use artsdb::btree::{BTree, SearchMode};
 use artsdb::buffer::BufferPool;
 use artsdb::buffer_pool_manager::BufferPoolManager;
 
-use artsdb::disk::{DiskManager, PageId};
+use artsdb::disk::DiskManager;
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("test.btr")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
+    let meta_page_id = bufmgr
+        .catalog_root()
+        .context("database has no catalog root")?;
+    let btree = BTree::new(meta_page_id);
     let mut iter = btree.search(&mut bufmgr, SearchMode::Key(b"Hyogo".to_vec()))?;
     let (key, value) = iter.next(&mut bufmgr)?.unwrap();
     println!("{:02x?} = {:02x?}", key, value);
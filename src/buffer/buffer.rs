@@ -1,50 +1,83 @@
 use std::{
-    cell::{Cell, RefCell},
     io,
-    rc::Rc,
+    sync::{atomic::AtomicBool, Arc},
 };
 
-use crate::disk::{PageId, PAGE_SIZE};
+use parking_lot::RwLock;
+
+use super::policy::{EvictionPolicy, Policy};
+use crate::disk::{self, PageId, PAGE_SIZE};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error(transparent)]
+    Disk(#[from] disk::Error),
     #[error("no free buffer available in buffer pool")]
     NoFreeBuffer,
+    #[error("page {0:?} is pinned")]
+    PagePinned(PageId),
+    #[error("buffer pool of {pool_size} pages exceeds the memory budget of {budget} bytes")]
+    MemoryBudgetExceeded { pool_size: usize, budget: usize },
 }
 
-// u8の型の配列をPAGE_SIXE(4096個)確保する。
-pub type Page = [u8; PAGE_SIZE];
+// ページデータを保存するu8の配列。大きさはデータベースのページサイズに合わせて確保する。
+pub type Page = Box<[u8]>;
 
+/*
+    複数のスレッドから共有されるバッファ。
+    ページデータはラッチ(RwLock)で守られており、同時に複数のスレッドが読むか、一つのスレッドだけが書き換えられる。
+    ページデータを書き換えたスレッドは、書き込みラッチを持ったままis_dirtyを立てる。
+    ページデータの先頭PAGE_HEADER_SIZEバイトはLSNを置くヘッダなので、直接書き換えるときもその後ろだけを使う。
+*/
 #[derive(Debug)]
 pub struct Buffer {
     // Disk側のpageID
     pub page_id: PageId,
-    // バッファとしてデータを保存するPAGE_SIZEの大きさの配列
-    pub page: RefCell<Page>,
+    // バッファとしてデータを保存するページサイズの大きさの配列。ガードがラッチを持ち続けられるようにArcで共有する
+    pub page: Arc<RwLock<Page>>,
     // バッファの値が書き換えられており、ディスク上の値が古くなっている状態のこと
-    pub is_dirty: Cell<bool>,
+    pub is_dirty: AtomicBool,
+    /*
+        ページを読み込み終えたかどうか。立っていない間は、割り当てたスレッドが書き込みラッチを持って読み込んでいる。
+        読み込みに失敗した場合は立たないまま捨てられるので、ラッチで待っていたスレッドはバッファを探し直す。
+    */
+    pub(crate) loaded: AtomicBool,
 }
 
-impl Default for Buffer {
-    fn default() -> Self {
+impl Buffer {
+    pub fn new(page_size: usize) -> Self {
+        // どのページも入っていない
+        Self::new_with(
+            PageId::INVALID_PAGE_ID,
+            vec![0u8; page_size].into_boxed_slice(),
+        )
+    }
+
+    // page_idのページを入れるバッファを、pageのメモリを使って作る
+    pub(crate) fn new_with(page_id: PageId, page: Page) -> Self {
         Self {
-            page_id: Default::default(),
-            page: RefCell::new([0u8; PAGE_SIZE]),
-            is_dirty: Cell::new(false),
+            page_id,
+            page: Arc::new(RwLock::new(page)),
+            is_dirty: AtomicBool::new(false),
+            loaded: AtomicBool::new(false),
         }
     }
 }
 
-#[derive(Debug, Default, Copy, Clone)]
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::new(PAGE_SIZE)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct BufferId(pub usize);
 
 #[derive(Default)]
 pub struct Frame {
-    // bufferの使用回数。多いほどクリアされづらくなる
-    pub usage_count: u64,
-    pub buffer: Rc<Buffer>,
+    pub buffer: Arc<Buffer>,
 }
 
 /*
@@ -55,67 +88,203 @@ pub struct Frame {
 
     BufferPoolには、
     新しいBufferを追加するためのメソッドと、古いBufferを捨てるためのメソッドが定義されています。
-    古いBufferを捨てるメソッドでは、Clock-sweepアルゴリズムを使用して、再利用しなさそうなBufferを捨てるようにしています。
+    どのBufferを捨てるかは、BufferPoolを作るときに選んだEvictionPolicyが決めます。
+    何も選ばなければClock-sweepアルゴリズムを使用して、再利用しなさそうなBufferを捨てるようにしています。
 */
 pub struct BufferPool {
     pub buffers: Vec<Frame>,
-    pub next_victim_id: BufferId,
+    /*
+        どのページも入っていないバッファを積んだもの。追い出すバッファを選ぶ前に、まずここから使う。
+        空きでなくなったバッファは取り出すときにfreeで確かめて捨てるので、積んだまま残っていることもある
+    */
+    free_list: Vec<BufferId>,
+    // バッファごとに、どのページも入っていないかどうか
+    free: Vec<bool>,
+    free_frames: usize,
+    policy: Box<dyn EvictionPolicy>,
+    // 各バッファのページの大きさ
+    page_size: usize,
 }
 
 impl BufferPool {
     pub fn new(pool_size: usize) -> Self {
+        Self::new_with(pool_size, Policy::default())
+    }
+
+    pub fn new_with(pool_size: usize, policy: Policy) -> Self {
         let mut buffers: Vec<Frame> = vec![];
         buffers.resize_with(pool_size, Default::default);
-        let next_victim_id = BufferId::default();
+        // 先頭のバッファから使われるように、逆順に積んでおく
+        let free_list = (0..pool_size).rev().map(BufferId).collect();
         Self {
             buffers,
-            next_victim_id,
+            free_list,
+            free: vec![true; pool_size],
+            free_frames: pool_size,
+            policy: policy.build(pool_size),
+            page_size: PAGE_SIZE,
         }
     }
 
-    fn size(&self) -> usize {
+    pub fn size(&self) -> usize {
         self.buffers.len()
     }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    // どのページも入っていないバッファの数
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // すべてのバッファをpage_sizeの大きさのページに作り直す
+    pub fn set_page_size(&mut self, page_size: usize) {
+        for (i, frame) in self.buffers.iter_mut().enumerate() {
+            frame.buffer = Arc::new(Buffer::new(page_size));
+            self.policy.on_remove(BufferId(i));
+        }
+        self.free_list = (0..self.size()).rev().map(BufferId).collect();
+        self.free.fill(true);
+        self.free_frames = self.size();
+        self.page_size = page_size;
+    }
+
+    /*
+        バッファの数をpool_sizeに変える。増やしたバッファは空いているバッファになる。
+        減らす場合は後ろのバッファから取り除くので、呼び出し側で貸出中でないことを確かめ、変更を書き戻しておく
+    */
+    pub fn resize(&mut self, pool_size: usize) {
+        let old_size = self.size();
+        let page_size = self.page_size;
+        self.buffers.truncate(pool_size);
+        self.buffers.resize_with(pool_size, || Frame {
+            buffer: Arc::new(Buffer::new(page_size)),
+        });
+        self.free.resize(pool_size, true);
+        let free = &self.free;
+        self.free_list
+            .retain(|buffer_id| buffer_id.0 < pool_size && free[buffer_id.0]);
+        self.free_list
+            .extend((old_size..pool_size).rev().map(BufferId));
+        self.free_frames = self.free.iter().filter(|&&free| free).count();
+        self.policy.resize(pool_size);
+    }
+
     /*
-        Clock-sweepアルゴリズムは、特定の条件を満たすフレームを置き換えるために使用されます。
-        置き換えるフレームを選択するために、clock-sweepアルゴリズムは単純なカウンタを使用し、
-        バッファプール内のすべてのフレームを周回します。
-        フレームが使用されていない場合、または使用回数が低い場合は、そのフレームを置き換えることができます。
-        使用回数が高いフレームは、しばらく使用され続ける可能性が高いため、置き換えるのが難しいとされます。
-        clock-sweepアルゴリズムは、バッファプールが大きくなるにつれて、
-        時間がかかる傾向があるため、大規模なシステムでは使用しない方が良い場合もあります。
+        ページを読み込むバッファを選ぶ。空いているバッファがあればそれを、なければ追い出すバッファを選ぶ。
+        空いているバッファはreserveされるまで空きのまま残る。
+        貸出中でないバッファがなければNone
     */
     pub fn evict(&mut self) -> Option<BufferId> {
-        let pool_size = self.size();
-        let mut consecutive_pinned = 0;
-        // bufferpoolの全てのbufferを巡回しながら捨てるものを決める
-        let victim_id = loop {
-            let next_victim_id = self.next_victim_id;
-            let frame = &mut self[next_victim_id];
-            if frame.usage_count == 0 {
-                break self.next_victim_id;
+        while let Some(&buffer_id) = self.free_list.last() {
+            if self.free[buffer_id.0] {
+                return Some(buffer_id);
             }
-            // 巡回中に貸出中でなければデクリメントされる
-            if Rc::get_mut(&mut frame.buffer).is_some() {
-                frame.usage_count -= 1;
-                consecutive_pinned = 0;
-            } else {
-                /*
-                    貸出中だった場合はconsective_pinnedカウンタを増やす。
-                    カウンタがbuffer_poolと同じになった場合にはすべてのbufferが貸出中ということなので、
-                    諦めてNoneを返す
-                */
-                consecutive_pinned += 1;
-                if consecutive_pinned >= pool_size {
-                    return None;
-                }
-            }
-            self.next_victim_id = self.increment_id(self.next_victim_id);
-        };
-        Some(victim_id)
+            self.free_list.pop();
+        }
+        let buffers = &self.buffers;
+        self.policy
+            .victim(&|buffer_id| is_pinned(&buffers[buffer_id.0].buffer))
     }
 
-    fn increment_id(&self, buffer_id: BufferId) -> BufferId {
-        BufferId((buffer_id.0 + 1) % self.size())
+    /*
+        buffer_idのバッファを追い出せないかどうか。貸し出している間と、
+        バックグラウンドの書き戻しが(バッファを借りずに)ラッチだけを持っている間は追い出せない
+    */
+    pub fn is_pinned(&self, buffer_id: BufferId) -> bool {
+        is_pinned(&self[buffer_id].buffer)
+    }
+
+    /*
+        貸出中でないバッファがあれば、そのラッチを返す。
+        evictで選べなかったのに貸出中でないバッファは、書き戻しがラッチを持っているか、たった今外したところ
+    */
+    pub fn unpinned_latch(&self) -> Option<Arc<RwLock<Page>>> {
+        self.buffers
+            .iter()
+            .map(|frame| &frame.buffer)
+            .find(|buffer| Arc::strong_count(buffer) == 1)
+            .map(|buffer| Arc::clone(&buffer.page))
+    }
+
+    /*
+        バッファの数をpool_sizeに減らすために追い出すバッファを、追い出しの方針で選ぶ。
+        ページの入っているバッファがpool_size個に収まるだけ選び、貸出中でないバッファが足りなければNoneを返す
+    */
+    pub fn shrink_victims(&mut self, pool_size: usize) -> Option<Vec<BufferId>> {
+        let excess = (self.size() - self.free_frames).saturating_sub(pool_size);
+        let mut chosen = vec![false; self.size()];
+        let mut victims = Vec::with_capacity(excess);
+        let buffers = &self.buffers;
+        let free = &self.free;
+        for _ in 0..excess {
+            let victim = self.policy.victim(&|buffer_id| {
+                chosen[buffer_id.0] || free[buffer_id.0] || is_pinned(&buffers[buffer_id.0].buffer)
+            })?;
+            chosen[victim.0] = true;
+            victims.push(victim);
+        }
+        Some(victims)
+    }
+
+    pub fn is_free(&self, buffer_id: BufferId) -> bool {
+        self.free[buffer_id.0]
+    }
+
+    // fromのバッファを、page_idのページとともに空いているtoへ移す。貸出中のバッファも移せる
+    pub fn move_buffer(&mut self, from: BufferId, to: BufferId, page_id: PageId) {
+        self.buffers.swap(from.0, to.0);
+        self.record_load(to, page_id);
+        self.record_remove(from);
+    }
+
+    // 追い出される順に並べたバッファ。空のバッファも含む
+    pub fn eviction_order(&self) -> Vec<BufferId> {
+        self.policy.eviction_order()
+    }
+
+    // buffer_idのバッファにページを入れるので、空いているバッファから外す
+    pub fn reserve(&mut self, buffer_id: BufferId) {
+        if self.free[buffer_id.0] {
+            self.free[buffer_id.0] = false;
+            self.free_frames -= 1;
+        }
+    }
+
+    // buffer_idのバッファにpage_idのページを読み込んだ
+    pub fn record_load(&mut self, buffer_id: BufferId, page_id: PageId) {
+        self.reserve(buffer_id);
+        self.policy.on_load(buffer_id, page_id);
+    }
+
+    // buffer_idのバッファに入っているページの使用回数。追い出しの方針が数えている
+    pub fn usage_count(&self, buffer_id: BufferId) -> u64 {
+        self.policy.usage_count(buffer_id)
     }
+
+    // buffer_idのバッファに入っているページが再び使われた
+    pub fn record_hit(&mut self, buffer_id: BufferId) {
+        self.policy.on_hit(buffer_id);
+    }
+
+    // buffer_idのバッファが空になった。次にページを読み込むときに使われる
+    pub fn record_remove(&mut self, buffer_id: BufferId) {
+        self.policy.on_remove(buffer_id);
+        if !self.free[buffer_id.0] {
+            self.free[buffer_id.0] = true;
+            self.free_frames += 1;
+            // 空きでなくなったバッファが積もりすぎないように、ときどき取り除く
+            if self.free_list.len() >= self.size() * 2 {
+                let free = &self.free;
+                self.free_list.retain(|buffer_id| free[buffer_id.0]);
+            }
+            self.free_list.push(buffer_id);
+        }
+    }
+}
+
+fn is_pinned(buffer: &Arc<Buffer>) -> bool {
+    Arc::strong_count(buffer) > 1 || buffer.page.is_locked()
 }
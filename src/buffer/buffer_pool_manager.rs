@@ -1,108 +1,1068 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::{Index, IndexMut};
-use std::rc::Rc;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
-use super::buffer::{Buffer, BufferId, BufferPool, Error, Frame};
+use parking_lot::{ArcRwLockReadGuard, Mutex, MutexGuard, RawRwLock, RwLock};
+
+use super::buffer::{Buffer, BufferId, BufferPool, Error, Frame, Page};
+use super::guard::{PageReadGuard, PageWriteGuard};
+use super::stats::{BufferPoolSnapshot, BufferPoolStats, Counters, ResidentPage};
+use super::strategy::AccessStrategy;
+use super::wal::{page_lsn, WriteAheadLog, PAGE_HEADER_SIZE};
+use super::warm_up::WarmList;
 use crate::disk::{DiskManager, PageId};
 
-/*
-    バッファプール管理は、ディスクからのページデータの読み書きを効率化するために、データをメモリ上にキャッシュして管理する役割を担っています。
-*/
-pub struct BufferPoolManager {
-    disk: DiskManager,
+// バッファプールと、どのページがどのバッファに入っているかの対応。まとめて一つのロックで守る
+struct State {
     // メモリ上に管理するバッファプール
     pool: BufferPool,
     // どのページのデータがどのバッファに入っているかの対応関係を管理する
     page_table: HashMap<PageId, BufferId>,
+    // 最後にfetch_pageしたページと、そこまでページIDの順に続けて読まれた回数
+    last_fetched: PageId,
+    sequential_run: usize,
+}
+
+/*
+    ページを読み込むために割り当てたバッファ。
+    割り当てたバッファの書き込みラッチを取ってからstateのロックを外し、ロックの外で書き戻しと読み込みを行う。
+*/
+struct Reserved {
+    buffer_id: BufferId,
+    buffer: Arc<Buffer>,
+    // バッファから追い出したページ
+    evicted: PageId,
+    // 追い出したページが変更されていて、書き戻す必要があるかどうか
+    write_back: bool,
+}
+
+// 書き戻すページ。読み込みラッチを持っている間は、ページが書き換えられることも追い出されることもない
+struct WriteBack {
+    page_id: PageId,
+    page: ArcRwLockReadGuard<RawRwLock, Page>,
+}
+
+impl WriteBack {
+    fn new(page_id: PageId, page: ArcRwLockReadGuard<RawRwLock, Page>) -> Self {
+        Self { page_id, page }
+    }
+}
+
+#[derive(Clone)]
+pub struct BufferPoolOptions {
+    /*
+        ページIDの順に続けて読まれていることを検出したときに、先に読み込んでおくページの数。
+        バッファの数の1/4を超える分は読み込まない。0なら先読みしない
+    */
+    pub read_ahead: usize,
+    /*
+        バッファのページデータに使ってよいメモリの大きさ(バイト)。
+        作るときにこれを超えるバッファプールを渡された場合は、収まるところまでバッファを減らす
+    */
+    pub memory_budget: Option<usize>,
+    // 変更されたページを書き戻す前に、ページのLSNまでのログを永続化させるWAL
+    pub wal: Option<Arc<dyn WriteAheadLog>>,
+}
+
+impl Default for BufferPoolOptions {
+    fn default() -> Self {
+        Self {
+            read_ahead: 8,
+            memory_budget: None,
+            wal: None,
+        }
+    }
+}
+
+impl fmt::Debug for BufferPoolOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPoolOptions")
+            .field("read_ahead", &self.read_ahead)
+            .field("memory_budget", &self.memory_budget)
+            .field("wal", &self.wal.is_some())
+            .finish()
+    }
+}
+
+/*
+    バッファプール管理は、ディスクからのページデータの読み書きを効率化するために、データをメモリ上にキャッシュして管理する役割を担っています。
+
+    複数のスレッドから同時に使えるように、バッファプールとDiskManagerはそれぞれロックで守られています。
+    デッドロックしないように、ロックは「ページのラッチ → stateのロック → diskのロック」の順にだけ取ります。
+    ページを追い出すときは、貸出中でなく、バックグラウンドの書き戻しがラッチを持っていないバッファだけを扱います。
+
+    ディスクの読み書きやWALの永続化はstateのロックの外で行います。
+    stateのロックを持っている間にバッファを割り当てて書き込みラッチを取り、読み書きが終わるまでラッチで他のスレッドを待たせます。
+    ページの読み込みは同時に行えるように、DiskManagerは読み書きロックで守ります。
+*/
+pub struct BufferPoolManager {
+    disk: RwLock<DiskManager>,
+    state: Mutex<State>,
+    counters: Counters,
+    options: BufferPoolOptions,
 }
 
 impl BufferPoolManager {
     pub fn new(disk: DiskManager, pool: BufferPool) -> Self {
-        let page_table = HashMap::new();
-        Self {
-            disk,
+        Self::build(disk, pool, BufferPoolOptions::default())
+    }
+
+    // memory_budgetが1ページにも満たない場合はMemoryBudgetExceededを返します。
+    pub fn new_with(
+        disk: DiskManager,
+        pool: BufferPool,
+        options: BufferPoolOptions,
+    ) -> Result<Self, Error> {
+        if let Some(budget) = options.memory_budget {
+            if budget < disk.data_size() {
+                return Err(Error::MemoryBudgetExceeded {
+                    pool_size: 1,
+                    budget,
+                });
+            }
+        }
+        Ok(Self::build(disk, pool, options))
+    }
+
+    fn build(disk: DiskManager, mut pool: BufferPool, options: BufferPoolOptions) -> Self {
+        // バッファの大きさを、データベースが1ページに読み書きするページデータの大きさに合わせる
+        if disk.data_size() != pool.page_size() {
+            pool.set_page_size(disk.data_size());
+        }
+        if let Some(budget) = options.memory_budget {
+            pool.resize(pool.size().min(budget / pool.page_size()));
+        }
+        let state = State {
             pool,
-            page_table,
+            page_table: HashMap::new(),
+            last_fetched: PageId::INVALID_PAGE_ID,
+            sequential_run: 0,
+        };
+        Self {
+            disk: RwLock::new(disk),
+            state: Mutex::new(state),
+            counters: Counters::default(),
+            options,
         }
     }
     /*
         ページIDを指定して、対応するページデータを含むバッファを返します。
         もしページデータがバッファプールにない場合、ディスクから読み込んでバッファプールに格納します。また、必要に応じて古いバッファをディスクに書き戻します。
     */
-    pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<Buffer>, Error> {
-        // pageがbuffer_poolにある場合はそのバッファを貸し出す
-        if let Some(&buffer_id) = self.page_table.get(&page_id) {
-            let frame = &mut self.pool[buffer_id];
-            frame.usage_count += 1;
-            return Ok(Rc::clone(&frame.buffer));
-        }
+    pub fn fetch_page(&self, page_id: PageId) -> Result<Arc<Buffer>, Error> {
+        self.fetch_page_inner(page_id, None)
+    }
 
-        // これから読み込むページを格納するbufferを決定する
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
-        let frame = &mut self.pool[buffer_id];
-        let evict_page_id = frame.buffer.page_id;
-        {
-            // 取得したbufferがis_dirtyだった場合は、そのバッファをdiskに書き出す。
-            // is_dirtyはバッファの内容が変更されていて、disk上の内容が古くなっていることを示す
-            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
-            if buffer.is_dirty.get() {
-                self.disk
-                    .write_page_data(evict_page_id, buffer.page.get_mut())?;
-            }
-            buffer.page_id = page_id;
-            buffer.is_dirty.set(false);
-
-            // ページを読み出す。
-            self.disk.read_page_data(page_id, buffer.page.get_mut())?;
-            frame.usage_count = 1;
-        }
-        // バッファに入っているページが入れ替わったので、page_tableを更新する
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
-        self.page_table.insert(page_id, buffer_id);
+    /*
+        fetch_pageと同じですが、ページを読み込むバッファをstrategyのリングから選びます。
+        大量のページを一度だけ読む処理で使うと、よく使われるページを追い出さずに済みます。
+    */
+    pub fn fetch_page_with(
+        &self,
+        page_id: PageId,
+        strategy: &mut AccessStrategy,
+    ) -> Result<Arc<Buffer>, Error> {
+        self.fetch_page_inner(page_id, Some(strategy))
+    }
+
+    fn fetch_page_inner(
+        &self,
+        page_id: PageId,
+        mut strategy: Option<&mut AccessStrategy>,
+    ) -> Result<Arc<Buffer>, Error> {
+        let page = self.load_page(page_id, strategy.as_deref_mut())?;
+        let mut state = self.state.lock();
+        // ページIDの順に続けて読まれていれば、この後に読まれるページを先に読み込んでおく
+        if state.last_fetched.to_u64().wrapping_add(1) == page_id.to_u64() {
+            state.sequential_run += 1;
+        } else {
+            state.sequential_run = 0;
+        }
+        state.last_fetched = page_id;
+        let mut read_ahead = self.options.read_ahead.min(state.pool.size() / 4);
+        // リングを使う場合は、先読みしたページでリングが埋まってしまわないようにする
+        if let Some(strategy) = &strategy {
+            read_ahead = read_ahead.min(strategy.ring_size(state.pool.size()) / 2);
+        }
+        let read_ahead = read_ahead as u64;
+        let next = page_id.to_u64() + 1;
+        /*
+            先読みした範囲の半分まで読み進んだら、次の範囲を読み込む。
+            一ページずつではなく、ある程度まとまった数のページを一度に読み込むため
+        */
+        let trigger = PageId(page_id.to_u64() + read_ahead.div_ceil(2));
+        if state.sequential_run >= 1 && read_ahead > 0 && !state.page_table.contains_key(&trigger) {
+            drop(state);
+            let page_ids: Vec<_> = (next..next + read_ahead).map(PageId).collect();
+            self.read_ahead(&page_ids, strategy);
+        }
         Ok(page)
     }
 
+    fn load_page(
+        &self,
+        page_id: PageId,
+        mut strategy: Option<&mut AccessStrategy>,
+    ) -> Result<Arc<Buffer>, Error> {
+        let mut state = self.state.lock();
+        let buffer_id = loop {
+            let found;
+            (state, found) = self.lookup(state, page_id);
+            // pageがbuffer_poolにある場合はそのバッファを貸し出す
+            if let Some(buffer_id) = found {
+                // リングを使う読み込みは一度きりなので、使用回数を増やしてページを残りやすくすることはしない
+                if strategy.is_none() || state.pool.usage_count(buffer_id) == 0 {
+                    state.pool.record_hit(buffer_id);
+                }
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Arc::clone(&state.pool[buffer_id].buffer));
+            }
+
+            // これから読み込むページを格納するbufferを決定する
+            let evicted = self.evict(&mut state, strategy.as_deref_mut());
+            if !matches!(evicted, Ok(None)) {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(buffer_id) = evicted? {
+                break buffer_id;
+            }
+        };
+        let reserved = self.reserve(&mut state, buffer_id, page_id);
+        let buffer = Arc::clone(&reserved.buffer);
+        // 割り当てたばかりのバッファにはまだ誰も辿り着けないので、stateのロックを持ったままでも待たずに取れる
+        let mut page = buffer.page.write();
+        drop(state);
+
+        // 追い出したページが変更されていれば書き戻してから、ページを読み出す
+        if let Err(e) = self.write_back_evicted(&reserved, &page) {
+            self.restore_evicted(&reserved, &mut page);
+            return Err(e);
+        }
+        let result = self.disk.read().read_page_data(page_id, &mut page);
+        self.finish_load(&reserved, &mut page, result.is_ok());
+        result?;
+        drop(page);
+        Ok(buffer)
+    }
+
+    /*
+        page_tableからpage_idのページが入っているバッファを探す。
+        ページを読み込んでいる途中や、追い出したページを書き戻している途中のバッファであれば、
+        stateのロックを外してラッチで終わるのを待ち、探し直す。
+    */
+    fn lookup<'a>(
+        &'a self,
+        mut state: MutexGuard<'a, State>,
+        page_id: PageId,
+    ) -> (MutexGuard<'a, State>, Option<BufferId>) {
+        loop {
+            let Some(&buffer_id) = state.page_table.get(&page_id) else {
+                return (state, None);
+            };
+            let buffer = &state.pool[buffer_id].buffer;
+            if buffer.page_id == page_id && buffer.loaded.load(Ordering::SeqCst) {
+                return (state, Some(buffer_id));
+            }
+            let buffer = Arc::clone(buffer);
+            drop(state);
+            drop(buffer.page.read());
+            state = self.state.lock();
+        }
+    }
+
+    /*
+        buffer_idのバッファをpage_idのページのために割り当てる。
+        バッファのページのメモリは新しいBufferに移し、読み込み終わるまではloadedを立てない。
+        追い出すページが変更されていれば、書き戻し終わるまでpage_tableに残しておき、
+        その間にそのページを探したスレッドには新しいBufferのラッチで待ってもらう。
+    */
+    fn reserve(&self, state: &mut State, buffer_id: BufferId, page_id: PageId) -> Reserved {
+        let frame = &mut state.pool[buffer_id];
+        // 貸出中でないバッファだけが選ばれるので、他に誰も持っていない
+        let old = Arc::get_mut(&mut frame.buffer).unwrap();
+        let evicted = old.page_id;
+        // page_tableから外されたバッファは、変更されていても書き戻さない
+        let write_back =
+            *old.is_dirty.get_mut() && state.page_table.get(&evicted) == Some(&buffer_id);
+        let buffer = Arc::new(Buffer::new_with(
+            page_id,
+            std::mem::take(&mut *old.page.write()),
+        ));
+        frame.buffer = Arc::clone(&buffer);
+        state.pool.reserve(buffer_id);
+        if !write_back {
+            self.remove_evicted(state, buffer_id, evicted);
+        }
+        if page_id != PageId::INVALID_PAGE_ID {
+            state.page_table.insert(page_id, buffer_id);
+            state.pool.record_load(buffer_id, page_id);
+        }
+        Reserved {
+            buffer_id,
+            buffer,
+            evicted,
+            write_back,
+        }
+    }
+
+    // 追い出したページがまだbuffer_idのバッファに対応していれば、page_tableから外す
+    fn remove_evicted(&self, state: &mut State, buffer_id: BufferId, evicted: PageId) {
+        if state.page_table.get(&evicted) == Some(&buffer_id) {
+            state.page_table.remove(&evicted);
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // 割り当てたバッファから追い出したページが変更されていれば、書き戻す。pageはまだ追い出したページの内容を持っている
+    fn write_back_evicted(&self, reserved: &Reserved, page: &[u8]) -> Result<(), Error> {
+        if reserved.write_back {
+            self.force_log(page)?;
+            self.disk.write().write_page_data(reserved.evicted, page)?;
+            self.counters
+                .dirty_write_backs
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    // 追い出したページを書き戻せなかったので、変更されたままのページとしてバッファに戻す
+    fn restore_evicted(&self, reserved: &Reserved, page: &mut Page) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let buffer = Buffer::new_with(reserved.evicted, std::mem::take(page));
+        buffer.is_dirty.store(true, Ordering::SeqCst);
+        buffer.loaded.store(true, Ordering::SeqCst);
+        state.pool[reserved.buffer_id].buffer = Arc::new(buffer);
+        let page_id = reserved.buffer.page_id;
+        if page_id != PageId::INVALID_PAGE_ID {
+            state.page_table.remove(&page_id);
+        }
+        state.pool.record_load(reserved.buffer_id, reserved.evicted);
+    }
+
+    /*
+        割り当てたバッファへの読み込みを終える。追い出したページを書き戻していれば、ここでpage_tableから外す。
+        読み込みに失敗した場合は中身が中途半端になっているので、どのページにも対応しない状態に戻す。
+    */
+    fn finish_load(&self, reserved: &Reserved, page: &mut Page, loaded: bool) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if reserved.write_back {
+            self.remove_evicted(state, reserved.buffer_id, reserved.evicted);
+        }
+        if loaded {
+            reserved.buffer.loaded.store(true, Ordering::SeqCst);
+            return;
+        }
+        let page_id = reserved.buffer.page_id;
+        if page_id != PageId::INVALID_PAGE_ID {
+            state.page_table.remove(&page_id);
+        }
+        let buffer = Buffer::new_with(PageId::INVALID_PAGE_ID, std::mem::take(page));
+        state.pool[reserved.buffer_id].buffer = Arc::new(buffer);
+        state.pool.record_remove(reserved.buffer_id);
+    }
+
     /*
         新しいページを作成し、そのページデータを含むバッファを返します。新しいページはディスクから割り当てられ、バッファプールに格納されます。
     */
-    pub fn create_page(&mut self) -> Result<Rc<Buffer>, Error> {
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
-        let frame = &mut self.pool[buffer_id];
-        let evict_page_id = frame.buffer.page_id;
-        let page_id = {
-            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
-            if buffer.is_dirty.get() {
-                self.disk
-                    .write_page_data(evict_page_id, buffer.page.get_mut())?;
-            }
-            let page_id = self.disk.allocate_page();
-            *buffer = Buffer::default();
-            buffer.page_id = page_id;
-            buffer.is_dirty.set(true);
-            frame.usage_count = 1;
-            page_id
-        };
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
-        self.page_table.insert(page_id, buffer_id);
-        Ok(page)
+    pub fn create_page(&self) -> Result<Arc<Buffer>, Error> {
+        self.create_page_inner(None)
+    }
+
+    // create_pageと同じですが、ページを置くバッファをstrategyのリングから選びます。一括ロードで使います。
+    pub fn create_page_with(&self, strategy: &mut AccessStrategy) -> Result<Arc<Buffer>, Error> {
+        self.create_page_inner(Some(strategy))
+    }
+
+    fn create_page_inner(
+        &self,
+        mut strategy: Option<&mut AccessStrategy>,
+    ) -> Result<Arc<Buffer>, Error> {
+        // ページIDはディスクから割り当てるまで決まらないので、どのページにも対応しないバッファとして先に確保する
+        let mut state = self.state.lock();
+        let buffer_id = loop {
+            if let Some(buffer_id) = self.evict(&mut state, strategy.as_deref_mut())? {
+                break buffer_id;
+            }
+        };
+        let reserved = self.reserve(&mut state, buffer_id, PageId::INVALID_PAGE_ID);
+        let placeholder = Arc::clone(&reserved.buffer);
+        let mut page = placeholder.page.write();
+        drop(state);
+
+        if let Err(e) = self.write_back_evicted(&reserved, &page) {
+            self.restore_evicted(&reserved, &mut page);
+            return Err(e);
+        }
+        let page_id = match self.disk.write().allocate_page() {
+            Ok(page_id) => page_id,
+            Err(e) => {
+                self.finish_load(&reserved, &mut page, false);
+                return Err(e.into());
+            }
+        };
+        page.fill(0);
+
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if reserved.write_back {
+            self.remove_evicted(state, buffer_id, reserved.evicted);
+        }
+        let buffer = Buffer::new_with(page_id, std::mem::take(&mut *page));
+        buffer.is_dirty.store(true, Ordering::SeqCst);
+        buffer.loaded.store(true, Ordering::SeqCst);
+        let buffer = Arc::new(buffer);
+        state.pool[buffer_id].buffer = Arc::clone(&buffer);
+        state.pool.record_load(buffer_id, page_id);
+        if let Some(stale) = state.page_table.insert(page_id, buffer_id) {
+            self.remove_stale(state, stale);
+        }
+        Ok(buffer)
+    }
+
+    /*
+        再利用したページIDに、解放される前の内容を持ったバッファが残っていたので捨てる。
+        解放されたページを先読みしたときや、解放と先読みがすれ違ったときに残る。
+        貸出中なら空にはできないが、page_tableから外れているので誰も辿り着かず、書き戻されることもない
+    */
+    fn remove_stale(&self, state: &mut State, buffer_id: BufferId) {
+        let pinned = state.pool.is_pinned(buffer_id);
+        let frame = &mut state.pool[buffer_id];
+        frame.buffer.is_dirty.store(false, Ordering::SeqCst);
+        if !pinned {
+            let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
+            buffer.page_id = PageId::INVALID_PAGE_ID;
+            state.pool.record_remove(buffer_id);
+        }
+    }
+
+    /*
+        これから使うページを伝えて、バッファプールに読み込んでおきます。
+        連続したページはまとめて読み込みます。読み込んだページの数を返します。
+    */
+    pub fn prefetch(&self, page_ids: &[PageId]) -> usize {
+        let mut page_ids = page_ids.to_vec();
+        page_ids.sort_by_key(|page_id| page_id.to_u64());
+        page_ids.dedup();
+        self.read_ahead(&page_ids, None)
+    }
+
+    /*
+        バッファプールに載っているページの一覧を、使用回数の多い順にpathへ保存します。
+        終了するときやチェックポイントで呼んでおくと、次に開いたときにwarm_upで読み込み直せます。
+    */
+    pub fn save_warm_list(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let list = {
+            let state = self.state.lock();
+            let mut pages: Vec<(u64, u64)> = state
+                .page_table
+                .iter()
+                .map(|(page_id, &buffer_id)| (page_id.to_u64(), state.pool.usage_count(buffer_id)))
+                .collect();
+            pages.sort_by_key(|&(page_id, usage_count)| (std::cmp::Reverse(usage_count), page_id));
+            WarmList {
+                database_id: self.disk.read().database_id(),
+                page_size: state.pool.page_size(),
+                pages,
+            }
+        };
+        list.save(path.as_ref())?;
+        Ok(())
+    }
+
+    /*
+        save_warm_listで保存したページを、空いているバッファに読み込みます。読み込んだページの数を返します。
+        一覧がない、壊れている、別のデータベースのものである場合は何も読み込みません。
+        もう存在しないページや読み込めないページは飛ばします。
+    */
+    pub fn warm_up(&self, path: impl AsRef<Path>) -> Result<usize, Error> {
+        // 他の処理をあまり待たせないように、少しずつロックを取り直して読み込む
+        const CHUNK_PAGES: usize = 64;
+        let list = WarmList::load(path.as_ref())?;
+        if list.database_id != self.disk.read().database_id()
+            || list.page_size != self.state.lock().pool.page_size()
+        {
+            return Ok(0);
+        }
+        let page_ids: Vec<PageId> = list.page_ids().collect();
+        let mut loaded = 0;
+        for chunk in page_ids.chunks(CHUNK_PAGES) {
+            let mut chunk: Vec<PageId> = {
+                let state = self.state.lock();
+                let free_frames = state.pool.free_frames();
+                if free_frames == 0 {
+                    break;
+                }
+                // 保存した後に解放されたページは、空いているバッファを数えるときにも含めない
+                let disk = self.disk.read();
+                chunk
+                    .iter()
+                    .copied()
+                    .filter(|page_id| {
+                        disk.is_valid_page_id(*page_id)
+                            && !disk.is_free_page(*page_id)
+                            && !state.page_table.contains_key(page_id)
+                    })
+                    .take(free_frames)
+                    .collect()
+            };
+            chunk.sort_by_key(|page_id| page_id.to_u64());
+            loaded += self.read_ahead(&chunk, None);
+        }
+        Ok(loaded)
+    }
+
+    /*
+        page_idsのうちバッファプールに載っていないページを、連続したものごとにまとめて読み込む。
+        先読みは呼び出し側の処理には必要ないので、空いているバッファがなくなったり読み込みに失敗したりしたら諦める。
+        読み込んだページの数を返す。
+    */
+    fn read_ahead(&self, page_ids: &[PageId], mut strategy: Option<&mut AccessStrategy>) -> usize {
+        let page_ids: Vec<PageId> = {
+            let state = self.state.lock();
+            let disk = self.disk.read();
+            page_ids
+                .iter()
+                .copied()
+                // 解放されたページは中身に意味がないので読み込まない
+                .filter(|page_id| {
+                    disk.is_valid_page_id(*page_id)
+                        && !disk.is_free_page(*page_id)
+                        && !state.page_table.contains_key(page_id)
+                })
+                .collect()
+        };
+        let mut loaded = 0;
+        for run in page_ids.chunk_by(|a, b| a.to_u64() + 1 == b.to_u64()) {
+            let n = self.read_run(run, strategy.as_deref_mut());
+            loaded += n;
+            if n < run.len() {
+                break;
+            }
+        }
+        self.counters
+            .prefetched_pages
+            .fetch_add(loaded as u64, Ordering::Relaxed);
+        loaded
+    }
+
+    // 連続したページを、先頭から読み込めるだけまとめて読み込む。読み込んだページの数を返す
+    fn read_run(&self, run: &[PageId], mut strategy: Option<&mut AccessStrategy>) -> usize {
+        let mut state = self.state.lock();
+        let mut reserved = vec![];
+        for &page_id in run {
+            // 他のスレッドが先に読み込んでいれば、そこまでにする
+            if state.page_table.contains_key(&page_id) {
+                break;
+            }
+            let Some(buffer_id) = victim(&mut state.pool, strategy.as_deref_mut()) else {
+                break;
+            };
+            reserved.push(self.reserve(&mut state, buffer_id, page_id));
+        }
+        if reserved.is_empty() {
+            return 0;
+        }
+        let buffers: Vec<Arc<Buffer>> = reserved
+            .iter()
+            .map(|reserved| Arc::clone(&reserved.buffer))
+            .collect();
+        // 割り当てたばかりのバッファにはまだ誰も辿り着けないので、ここでラッチを取っても待たされることはない
+        let mut pages: Vec<_> = buffers.iter().map(|buffer| buffer.page.write()).collect();
+        drop(state);
+
+        // 追い出したページを書き戻せなかったら、そのバッファから後ろは読み込まずに元に戻す
+        let mut n = reserved.len();
+        for (i, (reserved, page)) in reserved.iter().zip(&mut pages).enumerate() {
+            if i >= n {
+                if reserved.write_back {
+                    self.restore_evicted(reserved, page);
+                } else {
+                    self.finish_load(reserved, page, false);
+                }
+            } else if self.write_back_evicted(reserved, page).is_err() {
+                self.restore_evicted(reserved, page);
+                n = i;
+            }
+        }
+        if n == 0 {
+            return 0;
+        }
+        let result = {
+            let mut data: Vec<&mut [u8]> =
+                pages[..n].iter_mut().map(|page| &mut page[..]).collect();
+            self.disk.read().read_pages_data(run[0], &mut data)
+        };
+        for (reserved, page) in reserved.iter().zip(&mut pages).take(n) {
+            self.finish_load(reserved, page, result.is_ok());
+        }
+        if result.is_err() {
+            return 0;
+        }
+        n
+    }
+
+    /*
+        WALの規則を守るため、pageを書き戻す前に、ページのLSNまでのログを永続化させる。
+        ログの永続化は遅いので、stateのロックもディスクのロックも持たずに呼ぶ
+    */
+    fn force_log(&self, page: &[u8]) -> Result<(), Error> {
+        if let Some(wal) = &self.options.wal {
+            wal.flush_to(page_lsn(page))?;
+        }
+        Ok(())
+    }
+
+    /*
+        ページを読み込むバッファを選ぶ。すべて貸出中ならNoFreeBufferを返す。
+        貸出中でないバッファがどれもバックグラウンドで書き戻している途中なら、stateのロックを外して終わるのを待ち、Noneを返す。
+        待っている間に他のスレッドがstateを変えているので、呼び出し側は探し直す
+    */
+    fn evict(
+        &self,
+        state: &mut MutexGuard<State>,
+        strategy: Option<&mut AccessStrategy>,
+    ) -> Result<Option<BufferId>, Error> {
+        if let Some(buffer_id) = victim(&mut state.pool, strategy) {
+            return Ok(Some(buffer_id));
+        }
+        let Some(latch) = state.pool.unpinned_latch() else {
+            self.counters.no_free_buffer.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::NoFreeBuffer);
+        };
+        // 書き戻しは読み込みラッチを持っているので、書き込みラッチで外れるのを待つ
+        MutexGuard::unlocked(state, || drop(latch.write()));
+        Ok(None)
+    }
+
+    /*
+        ページを読み込みラッチを持ったまま貸し出します。ガードを捨てるとラッチが外れ、貸出も終わります。
+        同じスレッドで同じページの書き込みガードを持ったまま呼ぶとデッドロックします。
+    */
+    pub fn fetch_page_read(&self, page_id: PageId) -> Result<PageReadGuard, Error> {
+        Ok(PageReadGuard::new(self.fetch_page(page_id)?))
+    }
+
+    // ページを書き込みラッチを持ったまま貸し出します。ページを書き換えるとis_dirtyが立ちます。
+    pub fn fetch_page_write(&self, page_id: PageId) -> Result<PageWriteGuard, Error> {
+        Ok(PageWriteGuard::new(self.fetch_page(page_id)?))
+    }
+
+    // 新しいページを作成し、書き込みラッチを持ったまま貸し出します。
+    pub fn create_page_write(&self) -> Result<PageWriteGuard, Error> {
+        Ok(PageWriteGuard::new(self.create_page()?))
+    }
+
+    /*
+        ページを削除し、ディスク上の領域を再利用できるようにします。
+        バッファプールに載っている場合はバッファを空にします。貸出中のページは削除できません。
+    */
+    pub fn delete_page(&self, page_id: PageId) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        loop {
+            let found;
+            (state, found) = self.lookup(state, page_id);
+            let Some(buffer_id) = found else {
+                break;
+            };
+            if Arc::strong_count(&state.pool[buffer_id].buffer) > 1 {
+                return Err(Error::PagePinned(page_id));
+            }
+            // バックグラウンドで書き戻している途中なら、終わるのを待ってから探し直す
+            let latch = Arc::clone(&state.pool[buffer_id].buffer.page);
+            if latch.is_locked() {
+                MutexGuard::unlocked(&mut state, || drop(latch.write()));
+                continue;
+            }
+            let state = &mut *state;
+            let buffer = Arc::get_mut(&mut state.pool[buffer_id].buffer).unwrap();
+            // 削除したページが後で書き戻されないように、どのページにも対応しない状態にしておく
+            buffer.page_id = PageId::INVALID_PAGE_ID;
+            *buffer.is_dirty.get_mut() = false;
+            state.pool.record_remove(buffer_id);
+            state.page_table.remove(&page_id);
+            break;
+        }
+        drop(state);
+        self.disk.write().deallocate_page(page_id)?;
+        Ok(())
+    }
+
+    // データベースのカタログ(最初に辿るB-treeのメタページ)のページIDを返します。
+    pub fn catalog_root(&self) -> Option<PageId> {
+        self.disk.read().catalog_root()
+    }
+
+    pub fn set_catalog_root(&self, page_id: PageId) -> Result<(), Error> {
+        self.disk.write().set_catalog_root(page_id)?;
+        Ok(())
+    }
+
+    // バッファプール内の変更されたページデータをディスクに書き戻し、is_dirtyフラグをリセットします。ディスクへの同期も行われます。
+    pub fn flush(&self) -> Result<(), Error> {
+        let buffers = self.dirty_buffers();
+        self.write_back(buffers, true)?;
+        Ok(())
+    }
+
+    // 指定したページが変更されていればディスクに書き戻し、同期します。
+    pub fn flush_page(&self, page_id: PageId) -> Result<(), Error> {
+        let buffer = {
+            let state = self.state.lock();
+            state
+                .page_table
+                .get(&page_id)
+                .map(|&buffer_id| Arc::clone(&state.pool[buffer_id].buffer))
+        };
+        let buffers = buffer
+            .filter(|buffer| buffer.is_dirty.load(Ordering::SeqCst))
+            .into_iter()
+            .collect();
+        self.write_back(buffers, true)?;
+        Ok(())
+    }
+
+    /*
+        変更されたページを、次に追い出されそうなものから最大max_pages個だけ書き戻します。同期はしません。
+        追い出されそうなページを先に書いておくことで、追い出すときに書き込みを待たずに済むようにします。
+        バッファは借りずに読み込みラッチだけを持つので、書き戻している間も他のバッファは追い出せます。
+        書き戻したページの数を返します。
+    */
+    pub fn write_dirty_pages(&self, max_pages: usize) -> Result<usize, Error> {
+        let pages = {
+            let state = self.state.lock();
+            self.latch_dirty(&state, state.pool.eviction_order(), max_pages)
+        };
+        let written = self.write_latched(pages)?;
+        self.counters
+            .flushed_pages
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    /*
+        buffer_idsのうち変更されているページの読み込みラッチを、バッファを借りずに最大max_pages個取り、is_dirtyを下ろす。
+        他のスレッドが書き換えているページは、stateのロックを持ったまま待たずに飛ばす
+    */
+    fn latch_dirty(
+        &self,
+        state: &State,
+        buffer_ids: impl IntoIterator<Item = BufferId>,
+        max_pages: usize,
+    ) -> Vec<WriteBack> {
+        let mut pages = vec![];
+        for buffer_id in buffer_ids {
+            if pages.len() >= max_pages {
+                break;
+            }
+            let buffer = &state.pool[buffer_id].buffer;
+            if !buffer.loaded.load(Ordering::SeqCst)
+                || !buffer.is_dirty.load(Ordering::SeqCst)
+                || state.page_table.get(&buffer.page_id) != Some(&buffer_id)
+            {
+                continue;
+            }
+            if let Some(page) = buffer.page.try_read_arc() {
+                buffer.is_dirty.store(false, Ordering::SeqCst);
+                pages.push(WriteBack::new(buffer.page_id, page));
+            }
+        }
+        pages
+    }
+
+    // latch_dirtyでラッチを取ったページを書き込む。stateのロックを持たずに呼ぶ。書き込んだページの数を返す
+    fn write_latched(&self, mut pages: Vec<WriteBack>) -> Result<usize, Error> {
+        pages.sort_by_key(|page| page.page_id.to_u64());
+        if let Err(e) = self.write_runs(&pages.iter().collect::<Vec<_>>()) {
+            /*
+                ラッチを持っている間はページが追い出されないので、page_tableから元のバッファが見つかる。
+                resizeでバッファが移されていることもあるので、バッファIDは覚えておかずに探し直す
+            */
+            let state = self.state.lock();
+            for page in &pages {
+                if let Some(&buffer_id) = state.page_table.get(&page.page_id) {
+                    state.pool[buffer_id]
+                        .buffer
+                        .is_dirty
+                        .store(true, Ordering::SeqCst);
+                }
+            }
+            return Err(e);
+        }
+        Ok(pages.len())
     }
 
-    // バッファプール内のすべてのページデータをディスクに書き戻し、is_dirtyフラグをリセットします。ディスクへの同期も行われます。
+    // 変更されたページのバッファをすべて借りる
+    fn dirty_buffers(&self) -> Vec<Arc<Buffer>> {
+        let state = self.state.lock();
+        // 書き込んでいる間に追い出されないように、対象のバッファを借りておく
+        state
+            .page_table
+            .values()
+            .map(|&buffer_id| &state.pool[buffer_id].buffer)
+            .filter(|buffer| buffer.is_dirty.load(Ordering::SeqCst))
+            .map(Arc::clone)
+            .collect()
+    }
 
-    pub fn flush(&mut self) -> Result<(), Error> {
-        for (&page_id, &buffer_id) in self.page_table.iter() {
-            let frame = &self.pool[buffer_id];
-            let mut page = frame.buffer.page.borrow_mut();
-            self.disk.write_page_data(page_id, page.as_mut())?;
-            frame.buffer.is_dirty.set(false);
+    // buffersを書き戻す。書き戻したページの数を返す
+    fn write_back(&self, mut buffers: Vec<Arc<Buffer>>, sync: bool) -> Result<usize, Error> {
+        // ページIDの順に並べ、隣り合うページはまとめて書き込む
+        buffers.sort_by_key(|buffer| buffer.page_id.to_u64());
+        let mut pending: Vec<&Arc<Buffer>> = buffers.iter().collect();
+        let mut cleaned = vec![];
+        let mut result = Ok(());
+        while !pending.is_empty() && result.is_ok() {
+            /*
+                ラッチを持ったまま別のページのラッチを待つと、ガードを持ったまま他のページを借りるスレッドとデッドロックしうる。
+                取れるラッチだけを取って書き戻し、外してから残りをやり直す。
+                一つも取れなければ、他のラッチを持っていないので一つだけ待って取る
+            */
+            let mut pages = vec![];
+            let mut rest = vec![];
+            for buffer in pending {
+                match buffer.page.try_read_arc() {
+                    Some(page) => pages.push((buffer, page)),
+                    None => rest.push(buffer),
+                }
+            }
+            if pages.is_empty() {
+                let buffer = rest.remove(0);
+                pages.push((buffer, buffer.page.read_arc()));
+            }
+            /*
+                ページを読み込みラッチで守っている間にis_dirtyを下ろし、下ろしたバッファをcleanedに集める。
+                ラッチを外した後に書き換えられたページは、書き換えたスレッドが再びis_dirtyを立てる。
+            */
+            let pages: Vec<_> = pages
+                .into_iter()
+                .filter(|(buffer, _)| buffer.is_dirty.swap(false, Ordering::SeqCst))
+                .map(|(buffer, page)| {
+                    cleaned.push(buffer);
+                    WriteBack::new(buffer.page_id, page)
+                })
+                .collect();
+            result = self.write_runs(&pages.iter().collect::<Vec<_>>());
+            pending = rest;
         }
-        self.disk.sync()?;
+        let result = result.and_then(|()| {
+            if sync {
+                self.disk.write().sync()?;
+            }
+            Ok(())
+        });
+        // 同期が終わるまではディスク上の内容が失われうるので、失敗したらis_dirtyを戻す
+        if let Err(e) = result {
+            for buffer in cleaned {
+                buffer.is_dirty.store(true, Ordering::SeqCst);
+            }
+            return Err(e);
+        }
+        self.counters
+            .flushed_pages
+            .fetch_add(cleaned.len() as u64, Ordering::Relaxed);
+        Ok(cleaned.len())
+    }
+
+    // バッファの数を返します。
+    pub fn pool_size(&self) -> usize {
+        self.state.lock().pool.size()
+    }
+
+    /*
+        1ページに読み書きできるページデータの大きさを返します。fetch_pageで借りたBuffer::pageはこの大きさです。
+        DiskManager::data_sizeと同じで、ページサイズより小さいことがあります。
+        チェックサムに4バイト、暗号化する場合はさらに暗号の分(ChaCha20Poly1305Cipherでは28バイト)を使います。
+    */
+    pub fn data_size(&self) -> usize {
+        self.disk.read().data_size()
+    }
+
+    // ガードから読み書きできるページの本体の大きさを返します。data_sizeから先頭のヘッダの分を除いたものです。
+    pub fn body_size(&self) -> usize {
+        self.data_size() - PAGE_HEADER_SIZE
+    }
+
+    /*
+        動いているバッファプールのバッファの数をpool_sizeに変えます。
+        減らす場合は、残すバッファに収まらない分のページを追い出しの方針が選んだものから追い出し、
+        変更されていれば書き戻します。後ろの取り除くバッファに残ったページは、空いたバッファへ移します。
+        貸出中のページも移せますが、貸出中でないページだけでは足りなければNoFreeBufferを返し、何も変えません。
+    */
+    pub fn resize(&self, pool_size: usize) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(budget) = self.options.memory_budget {
+                let size = pool_size.checked_mul(state.pool.page_size());
+                if size.is_none_or(|size| size > budget) {
+                    return Err(Error::MemoryBudgetExceeded { pool_size, budget });
+                }
+            }
+            if pool_size >= state.pool.size() {
+                state.pool.resize(pool_size);
+                return Ok(());
+            }
+            let Some(victims) = state.pool.shrink_victims(pool_size) else {
+                self.counters.no_free_buffer.fetch_add(1, Ordering::Relaxed);
+                return Err(Error::NoFreeBuffer);
+            };
+            // 変更されたページはstateのロックの外で書き戻し、その間にstateが変わっているので最初からやり直す
+            let pages = self.latch_dirty(&state, victims.iter().copied(), usize::MAX);
+            if !pages.is_empty() {
+                let written = MutexGuard::unlocked(&mut state, || self.write_latched(pages))?;
+                self.counters
+                    .dirty_write_backs
+                    .fetch_add(written as u64, Ordering::Relaxed);
+                continue;
+            }
+            match self.shrink(&mut state, victims, pool_size) {
+                Some(latch) => MutexGuard::unlocked(&mut state, || drop(latch.write())),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /*
+        victimsを追い出し、後ろのバッファに残ったページを前の空いているバッファへ移してから、バッファの数をpool_sizeに減らす。
+        読み込みやページの作成の途中のバッファは、終わったときにバッファIDで戻ってくるので動かせない。
+        そのようなバッファがあれば減らさずに、終わるのを待つためのラッチを返す
+    */
+    fn shrink(
+        &self,
+        state: &mut State,
+        victims: Vec<BufferId>,
+        pool_size: usize,
+    ) -> Option<Arc<RwLock<Page>>> {
+        for buffer_id in victims {
+            let buffer = Arc::get_mut(&mut state.pool[buffer_id].buffer).unwrap();
+            let page_id = std::mem::replace(&mut buffer.page_id, PageId::INVALID_PAGE_ID);
+            self.remove_evicted(state, buffer_id, page_id);
+            state.pool.record_remove(buffer_id);
+        }
+        let mut free: Vec<BufferId> = (0..pool_size)
+            .map(BufferId)
+            .filter(|&buffer_id| state.pool.is_free(buffer_id))
+            .collect();
+        for buffer_id in (pool_size..state.pool.size()).map(BufferId) {
+            if state.pool.is_free(buffer_id) {
+                continue;
+            }
+            let buffer = &state.pool[buffer_id].buffer;
+            /*
+                ページを作っている途中のバッファはpage_tableに載っていないが、
+                追い出したページの書き戻しや読み込みと同じく、終わったときにバッファIDで戻ってくる
+            */
+            if !buffer.loaded.load(Ordering::SeqCst) {
+                return Some(Arc::clone(&buffer.page));
+            }
+            // ページIDを再利用されて残ったバッファには誰も辿り着かないので、移さずに取り除く
+            let page_id = buffer.page_id;
+            if state.page_table.get(&page_id) != Some(&buffer_id) {
+                continue;
+            }
+            // 追い出した分だけ空いているので、前のバッファは足りている
+            let to = free.pop().unwrap();
+            state.pool.move_buffer(buffer_id, to, page_id);
+            state.page_table.insert(page_id, to);
+        }
+        state.pool.resize(pool_size);
+        None
+    }
+
+    // 統計を返します。
+    pub fn stats(&self) -> BufferPoolStats {
+        let state = self.state.lock();
+        self.stats_locked(&state)
+    }
+
+    // 統計と、バッファプールに載っているページの一覧を返します。
+    pub fn snapshot(&self) -> BufferPoolSnapshot {
+        let state = self.state.lock();
+        let mut resident_pages: Vec<_> = state
+            .page_table
+            .iter()
+            .map(|(&page_id, &buffer_id)| {
+                let frame = &state.pool[buffer_id];
+                ResidentPage {
+                    page_id,
+                    buffer_id,
+                    usage_count: state.pool.usage_count(buffer_id),
+                    is_dirty: frame.buffer.is_dirty.load(Ordering::SeqCst),
+                    pinned: Arc::strong_count(&frame.buffer) > 1,
+                }
+            })
+            .collect();
+        resident_pages.sort_by_key(|page| page.page_id.to_u64());
+        BufferPoolSnapshot {
+            stats: self.stats_locked(&state),
+            resident_pages,
+        }
+    }
+
+    fn stats_locked(&self, state: &State) -> BufferPoolStats {
+        let counters = &self.counters;
+        BufferPoolStats {
+            pool_size: state.pool.size(),
+            resident_pages: state.page_table.len(),
+            pinned_frames: state
+                .pool
+                .buffers
+                .iter()
+                .filter(|frame| Arc::strong_count(&frame.buffer) > 1)
+                .count(),
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            dirty_write_backs: counters.dirty_write_backs.load(Ordering::Relaxed),
+            flushed_pages: counters.flushed_pages.load(Ordering::Relaxed),
+            prefetched_pages: counters.prefetched_pages.load(Ordering::Relaxed),
+            no_free_buffer: counters.no_free_buffer.load(Ordering::Relaxed),
+        }
+    }
+
+    // ページIDの順に並べたpagesを書き込む。隣り合うページごとにまとめ、すべてを一度に書き込む
+    fn write_runs(&self, pages: &[&WriteBack]) -> Result<(), Error> {
+        let data: Vec<&[u8]> = pages.iter().map(|page| page.page.as_ref()).collect();
+        if let Some(page) = data.iter().max_by_key(|page| page_lsn(page)) {
+            self.force_log(page)?;
+        }
+        let mut runs = vec![];
+        let mut start = 0;
+        for run in pages.chunk_by(|a, b| a.page_id.to_u64() + 1 == b.page_id.to_u64()) {
+            runs.push((run[0].page_id, &data[start..start + run.len()]));
+            start += run.len();
+        }
+        self.disk.write().write_page_runs(&runs)?;
         Ok(())
     }
 }
 
+/*
+    ページを読み込むバッファを選ぶ。strategyがあれば、リングの次のバッファを使い回す。
+    リングのバッファが貸出中だったり、他からも使われて使用回数が増えていたりすれば、共有のバッファプールから選んで入れ替える。
+*/
+fn victim(pool: &mut BufferPool, strategy: Option<&mut AccessStrategy>) -> Option<BufferId> {
+    let Some(strategy) = strategy else {
+        return pool.evict();
+    };
+    if let Some(buffer_id) = strategy.advance(pool.size()) {
+        // バッファプールが縮んでいれば、リングのバッファがもうないこともある
+        if buffer_id.0 < pool.size()
+            && !pool.is_pinned(buffer_id)
+            && pool.usage_count(buffer_id) <= 1
+        {
+            return Some(buffer_id);
+        }
+    }
+    let buffer_id = pool.evict()?;
+    strategy.put(buffer_id);
+    Some(buffer_id)
+}
+
 impl Index<BufferId> for BufferPool {
     type Output = Frame;
     fn index(&self, index: BufferId) -> &Self::Output {
@@ -119,50 +1079,833 @@ impl IndexMut<BufferId> for BufferPool {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::disk::PAGE_SIZE;
+    use crate::buffer::wal::{Lsn, PAGE_HEADER_SIZE};
+    use crate::disk::{self, DiskOptions, FaultConfig, FaultyStorage, MemoryStorage, PAGE_SIZE};
+    use std::sync::mpsc;
+    use std::time::Duration;
     use tempfile::tempfile;
 
+    // 暗号化も圧縮もしないデータベースで、1ページに読み書きできるページデータの大きさ。末尾の4バイトはチェックサムに使われる
+    const DATA_SIZE: usize = PAGE_SIZE - 4;
+
     #[test]
     fn test() {
-        let mut hello = Vec::with_capacity(PAGE_SIZE);
+        let mut hello = Vec::with_capacity(DATA_SIZE);
         hello.extend_from_slice(b"hello");
-        hello.resize(PAGE_SIZE, 0);
-        let mut world = Vec::with_capacity(PAGE_SIZE);
+        hello.resize(DATA_SIZE, 0);
+        let mut world = Vec::with_capacity(DATA_SIZE);
         world.extend_from_slice(b"world");
-        world.resize(PAGE_SIZE, 0);
+        world.resize(DATA_SIZE, 0);
 
         let disk = DiskManager::new(tempfile().unwrap()).unwrap();
         let pool = BufferPool::new(1);
-        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let bufmgr = BufferPoolManager::new(disk, pool);
         let page1_id = {
             let buffer = bufmgr.create_page().unwrap();
             assert!(bufmgr.create_page().is_err());
-            let mut page = buffer.page.borrow_mut();
+            let mut page = buffer.page.write();
             page.copy_from_slice(&hello);
-            buffer.is_dirty.set(true);
+            buffer.is_dirty.store(true, Ordering::SeqCst);
             buffer.page_id
         };
         {
             let buffer = bufmgr.fetch_page(page1_id).unwrap();
-            let page = buffer.page.borrow();
+            let page = buffer.page.read();
             assert_eq!(&hello, page.as_ref());
         }
         let page2_id = {
             let buffer = bufmgr.create_page().unwrap();
-            let mut page = buffer.page.borrow_mut();
+            let mut page = buffer.page.write();
             page.copy_from_slice(&world);
-            buffer.is_dirty.set(true);
+            buffer.is_dirty.store(true, Ordering::SeqCst);
             buffer.page_id
         };
         {
             let buffer = bufmgr.fetch_page(page1_id).unwrap();
-            let page = buffer.page.borrow();
+            let page = buffer.page.read();
             assert_eq!(&hello, page.as_ref());
         }
         {
             let buffer = bufmgr.fetch_page(page2_id).unwrap();
-            let page = buffer.page.borrow();
+            let page = buffer.page.read();
             assert_eq!(&world, page.as_ref());
         }
     }
+
+    #[test]
+    fn test_delete_page() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let pool = BufferPool::new(2);
+        let bufmgr = BufferPoolManager::new(disk, pool);
+        let page_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            assert!(bufmgr.delete_page(buffer.page_id).is_err());
+            buffer.page_id
+        };
+        bufmgr.delete_page(page_id).unwrap();
+        // 削除したページのIDが再利用される
+        let buffer = bufmgr.create_page().unwrap();
+        assert_eq!(page_id, buffer.page_id);
+    }
+
+    /*
+        ページの内容として、どのページの何回目の書き込みかが分かるデータを作る。
+        先頭のヘッダはLSNが0のままにしておき、ガードから書くときはヘッダの後ろだけを使う
+    */
+    fn page_data(page_id: PageId, version: u8) -> Vec<u8> {
+        let mut data = vec![version; DATA_SIZE];
+        data[..PAGE_HEADER_SIZE].fill(0);
+        data[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + 8]
+            .copy_from_slice(&page_id.to_u64().to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_crash_after_flush() {
+        for seed in 0..20 {
+            let config = FaultConfig {
+                seed,
+                torn_writes: true,
+                ..Default::default()
+            };
+            let storage = FaultyStorage::new(config);
+            let disk = DiskManager::with_storage(storage.clone(), DiskOptions::default()).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+            let page_ids: Vec<_> = (0..4)
+                .map(|_| {
+                    let buffer = bufmgr.create_page().unwrap();
+                    buffer
+                        .page
+                        .write()
+                        .copy_from_slice(&page_data(buffer.page_id, 1));
+                    buffer.page_id
+                })
+                .collect();
+            bufmgr.flush().unwrap();
+
+            // flushの後に書き換えたページは、追い出されたときに同期されないまま書き込まれる
+            for &page_id in &page_ids[..3] {
+                let buffer = bufmgr.fetch_page(page_id).unwrap();
+                buffer.page.write().copy_from_slice(&page_data(page_id, 2));
+                buffer.is_dirty.store(true, Ordering::SeqCst);
+            }
+            storage.crash();
+            drop(bufmgr);
+
+            let disk =
+                DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+            let mut buf = vec![0u8; DATA_SIZE];
+            // 書き換えていないページはflushしたときの内容のまま残る
+            disk.read_page_data(page_ids[3], &mut buf).unwrap();
+            assert_eq!(page_data(page_ids[3], 1), buf);
+            // 書き換えたページは古い内容か新しい内容のどちらかで、ちぎれた場合は壊れていることが検出される
+            for &page_id in &page_ids[..3] {
+                match disk.read_page_data(page_id, &mut buf) {
+                    Ok(()) => assert!(
+                        buf == page_data(page_id, 1) || buf == page_data(page_id, 2),
+                        "seed {}: page {:?} has inconsistent data",
+                        seed,
+                        page_id
+                    ),
+                    Err(disk::Error::Corrupted { page_id: id, .. }) => assert_eq!(page_id, id),
+                    Err(e) => panic!("seed {}: unexpected error {:?}", seed, e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_eviction_write_error() {
+        let storage = FaultyStorage::new(FaultConfig::default());
+        let disk = DiskManager::with_storage(storage.clone(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer
+                .page
+                .write()
+                .copy_from_slice(&page_data(buffer.page_id, 1));
+            buffer.page_id
+        };
+
+        // 追い出すページの書き込みに失敗しても、そのページはバッファプールに残る
+        storage.set_write_error_rate(1.0);
+        assert!(bufmgr.create_page().is_err());
+        assert!(bufmgr.flush().is_err());
+        {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            assert!(buffer.is_dirty.load(Ordering::SeqCst));
+            assert_eq!(page_data(page_id, 1), buffer.page.read().as_ref());
+        }
+
+        // 存在しないページの読み込みに失敗しても、追い出したページは正しく読み直せる
+        storage.set_write_error_rate(0.0);
+        assert!(bufmgr.fetch_page(PageId(100)).is_err());
+        {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            assert_eq!(page_id, buffer.page_id);
+            assert_eq!(page_data(page_id, 1), buffer.page.read().as_ref());
+        }
+        bufmgr.flush().unwrap();
+        storage.crash();
+        drop(bufmgr);
+
+        let disk = DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+        let mut buf = vec![0u8; DATA_SIZE];
+        disk.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(page_data(page_id, 1), buf);
+    }
+
+    #[test]
+    fn test_page_size() {
+        for page_size in [1024, 4096, 16 * 1024, 32 * 1024] {
+            let options = DiskOptions {
+                page_size,
+                ..Default::default()
+            };
+            let disk = DiskManager::with_storage(MemoryStorage::new(), options).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+            let page_ids: Vec<_> = (0..4u8)
+                .map(|i| {
+                    let buffer = bufmgr.create_page().unwrap();
+                    assert_eq!(page_size - 4, buffer.page.read().len());
+                    buffer.page.write().fill(i);
+                    buffer.page_id
+                })
+                .collect();
+            // 追い出されたページを読み直しても内容が変わらない
+            for (i, &page_id) in page_ids.iter().enumerate() {
+                let buffer = bufmgr.fetch_page(page_id).unwrap();
+                assert!(buffer.page.read().iter().all(|&b| b == i as u8));
+            }
+        }
+    }
+
+    #[test]
+    fn test_data_size() {
+        let cipher: Arc<dyn disk::PageCipher> =
+            Arc::new(disk::ChaCha20Poly1305Cipher::new(&[7; 32]));
+        let cases = [(None, PAGE_SIZE - 4), (Some(cipher), PAGE_SIZE - 4 - 28)];
+        for (cipher, data_size) in cases {
+            let options = DiskOptions {
+                cipher,
+                ..Default::default()
+            };
+            let storage = FaultyStorage::new(FaultConfig::default());
+            let disk = DiskManager::with_storage(storage.clone(), options.clone()).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+            assert_eq!(data_size, bufmgr.data_size());
+            assert_eq!(data_size - PAGE_HEADER_SIZE, bufmgr.body_size());
+
+            // ちょうどbody_sizeバイトを書き込んだページが、追い出しても開き直しても読み直せる
+            let body: Vec<u8> = (0..bufmgr.body_size()).map(|i| i as u8).collect();
+            let page_id = {
+                let mut page = bufmgr.create_page_write().unwrap();
+                assert_eq!(bufmgr.body_size(), page.len());
+                page.copy_from_slice(&body);
+                page.page_id()
+            };
+            bufmgr.create_page().unwrap();
+            assert_eq!(&body[..], &bufmgr.fetch_page_read(page_id).unwrap()[..]);
+            bufmgr.flush().unwrap();
+            storage.crash();
+            drop(bufmgr);
+
+            let disk = DiskManager::with_storage(storage.restart(), options).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+            assert_eq!(data_size, bufmgr.data_size());
+            assert_eq!(&body[..], &bufmgr.fetch_page_read(page_id).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BufferPoolManager>();
+
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(16));
+        // 各スレッドがページを作り、他のスレッドと並行して読み書きする
+        let page_ids: Vec<Vec<PageId>> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4u8)
+                .map(|t| {
+                    let bufmgr = &bufmgr;
+                    s.spawn(move || {
+                        let page_ids: Vec<_> = (0..8)
+                            .map(|_| {
+                                let buffer = bufmgr.create_page().unwrap();
+                                let data = page_data(buffer.page_id, t);
+                                buffer.page.write().copy_from_slice(&data);
+                                buffer.page_id
+                            })
+                            .collect();
+                        for _ in 0..10 {
+                            for &page_id in &page_ids {
+                                let buffer = bufmgr.fetch_page(page_id).unwrap();
+                                assert_eq!(page_data(page_id, t), buffer.page.read().as_ref());
+                            }
+                        }
+                        page_ids
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        bufmgr.flush().unwrap();
+        for (t, page_ids) in page_ids.iter().enumerate() {
+            for &page_id in page_ids {
+                let buffer = bufmgr.fetch_page(page_id).unwrap();
+                assert_eq!(page_data(page_id, t as u8), buffer.page.read().as_ref());
+            }
+        }
+    }
+
+    #[test]
+    fn test_page_guard() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_id = {
+            let mut page = bufmgr.create_page_write().unwrap();
+            let data = page_data(page.page_id(), 1);
+            page.copy_from_slice(&data[PAGE_HEADER_SIZE..]);
+            // 貸出中のページは追い出せない
+            assert!(bufmgr.create_page().is_err());
+            page.page_id()
+        };
+        bufmgr.flush().unwrap();
+        {
+            let page = bufmgr.fetch_page_read(page_id).unwrap();
+            assert_eq!(page_data(page_id, 1)[PAGE_HEADER_SIZE..], page[..]);
+            assert!(bufmgr.delete_page(page_id).is_err());
+        }
+        {
+            let mut page = bufmgr.fetch_page_write(page_id).unwrap();
+            page.copy_from_slice(&page_data(page_id, 2)[PAGE_HEADER_SIZE..]);
+        }
+        // 書き込みガードで書き換えたページはis_dirtyが立っており、追い出すときに書き戻される
+        assert!(bufmgr
+            .fetch_page(page_id)
+            .unwrap()
+            .is_dirty
+            .load(Ordering::SeqCst));
+        let other_id = bufmgr.create_page_write().unwrap().page_id();
+        assert_ne!(page_id, other_id);
+        let page = bufmgr.fetch_page_read(page_id).unwrap();
+        assert_eq!(page_data(page_id, 2)[PAGE_HEADER_SIZE..], page[..]);
+    }
+
+    #[test]
+    fn test_free_frames_keep_page0() {
+        // legacyの形式ではヘッダがないので、最初に作るページのIDが0になる
+        let options = DiskOptions {
+            legacy: true,
+            ..Default::default()
+        };
+        let disk = DiskManager::with_storage(MemoryStorage::new(), options).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(8));
+        // legacyの形式にはチェックサムがないので、ページ全体にデータを書ける
+        let page_data = |page_id, version| {
+            let mut data = page_data(page_id, version);
+            data.resize(PAGE_SIZE, version);
+            data
+        };
+        let page0 = bufmgr.create_page_write().unwrap().page_id();
+        assert_eq!(PageId(0), page0);
+        bufmgr
+            .fetch_page_write(page0)
+            .unwrap()
+            .copy_from_slice(&page_data(page0, 1)[PAGE_HEADER_SIZE..]);
+        let hot = bufmgr.fetch_page(page0).unwrap();
+        // 空いているバッファを使っても、バッファプールに載っているページ0の対応は消えない
+        for _ in 0..4 {
+            bufmgr.create_page().unwrap();
+            let buffer = bufmgr.fetch_page(page0).unwrap();
+            assert!(Arc::ptr_eq(&hot, &buffer));
+            assert_eq!(page_data(page0, 1), buffer.page.read().as_ref());
+        }
+        drop(hot);
+        bufmgr.flush().unwrap();
+        assert_eq!(
+            page_data(page0, 1)[PAGE_HEADER_SIZE..],
+            bufmgr.fetch_page_read(page0).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn test_flush_dirty_only() {
+        let storage = FaultyStorage::new(FaultConfig::default());
+        let disk = DiskManager::with_storage(storage.clone(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| bufmgr.create_page_write().unwrap().page_id())
+            .collect();
+        bufmgr.flush().unwrap();
+
+        // 変更されていないページは書き込まれないので、書き込みが失敗する状態でもflushできる
+        storage.set_write_error_rate(1.0);
+        bufmgr.flush().unwrap();
+        bufmgr
+            .fetch_page_write(page_ids[1])
+            .unwrap()
+            .copy_from_slice(&page_data(page_ids[1], 2)[PAGE_HEADER_SIZE..]);
+        bufmgr.flush_page(page_ids[0]).unwrap();
+        assert!(bufmgr.flush_page(page_ids[1]).is_err());
+        assert!(bufmgr
+            .fetch_page(page_ids[1])
+            .unwrap()
+            .is_dirty
+            .load(Ordering::SeqCst));
+
+        storage.set_write_error_rate(0.0);
+        bufmgr.flush_page(page_ids[1]).unwrap();
+        assert!(!bufmgr
+            .fetch_page(page_ids[1])
+            .unwrap()
+            .is_dirty
+            .load(Ordering::SeqCst));
+        storage.crash();
+        drop(bufmgr);
+
+        let disk = DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+        let mut buf = vec![0u8; DATA_SIZE];
+        disk.read_page_data(page_ids[1], &mut buf).unwrap();
+        assert_eq!(page_data(page_ids[1], 2), buf);
+    }
+
+    #[test]
+    fn test_stats() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| bufmgr.create_page_write().unwrap().page_id())
+            .collect();
+        // 3ページ目を作るときに、変更されていた1ページ目を追い出して書き戻している
+        let page = bufmgr.fetch_page_read(page_ids[2]).unwrap();
+        let _ = bufmgr.fetch_page(page_ids[2]).unwrap();
+        bufmgr.fetch_page(page_ids[0]).unwrap();
+        let snapshot = bufmgr.snapshot();
+        let stats = snapshot.stats;
+        assert_eq!(2, stats.pool_size);
+        assert_eq!(2, stats.resident_pages);
+        assert_eq!(1, stats.pinned_frames);
+        assert_eq!((2, 1), (stats.hits, stats.misses));
+        assert_eq!((2, 2), (stats.evictions, stats.dirty_write_backs));
+        assert!((stats.hit_ratio() - 2.0 / 3.0).abs() < 1e-9);
+
+        assert_eq!(
+            vec![(page_ids[0], 1, false, false), (page_ids[2], 3, true, true)],
+            snapshot
+                .resident_pages
+                .iter()
+                .map(|p| (p.page_id, p.usage_count, p.is_dirty, p.pinned))
+                .collect::<Vec<_>>()
+        );
+
+        // すべてのバッファが貸出中なら失敗した回数を数える
+        let _pinned = bufmgr.fetch_page(page_ids[0]).unwrap();
+        assert!(bufmgr.fetch_page(page_ids[1]).is_err());
+        assert_eq!(1, bufmgr.stats().no_free_buffer);
+        drop(page);
+        bufmgr.flush().unwrap();
+        assert_eq!(1, bufmgr.stats().flushed_pages);
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let storage = FaultyStorage::new(FaultConfig::default());
+        let disk = DiskManager::with_storage(storage.clone(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(16));
+        let page_ids: Vec<_> = (0..12)
+            .map(|_| {
+                let mut page = bufmgr.create_page_write().unwrap();
+                let data = page_data(page.page_id(), 1);
+                page.copy_from_slice(&data[PAGE_HEADER_SIZE..]);
+                page.page_id()
+            })
+            .collect();
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        // ページIDの順に読むと、続くページがまとめて読み込まれる
+        let disk = DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(16));
+        for &page_id in &page_ids[..8] {
+            let page = bufmgr.fetch_page_read(page_id).unwrap();
+            assert_eq!(page_data(page_id, 1)[PAGE_HEADER_SIZE..], page[..]);
+        }
+        let stats = bufmgr.stats();
+        assert_eq!((6, 2), (stats.hits, stats.misses));
+        assert_eq!(10, stats.prefetched_pages);
+        assert_eq!(12, stats.resident_pages);
+
+        // 読むページを前もって伝えることもできる
+        let disk = DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(16));
+        let hints = [
+            page_ids[7],
+            page_ids[2],
+            page_ids[3],
+            page_ids[7],
+            PageId(100),
+        ];
+        assert_eq!(3, bufmgr.prefetch(&hints));
+        for &page_id in &hints[..3] {
+            let page = bufmgr.fetch_page_read(page_id).unwrap();
+            assert_eq!(page_data(page_id, 1)[PAGE_HEADER_SIZE..], page[..]);
+        }
+        assert_eq!(0, bufmgr.stats().misses);
+    }
+
+    #[test]
+    fn test_access_strategy() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(16));
+        // 一括ロードで作ったページは、リングのバッファを使い回しながら書き戻される
+        let mut strategy = AccessStrategy::bulk_write();
+        let page_ids: Vec<_> = (0..64)
+            .map(|_| {
+                let buffer = bufmgr.create_page_with(&mut strategy).unwrap();
+                let data = page_data(buffer.page_id, 1);
+                buffer.page.write().copy_from_slice(&data);
+                buffer.page_id
+            })
+            .collect();
+        assert!(bufmgr.stats().resident_pages <= 4);
+
+        // よく使うページ
+        let hot = &page_ids[..4];
+        for _ in 0..3 {
+            for &page_id in hot {
+                bufmgr.fetch_page(page_id).unwrap();
+            }
+        }
+        // リングを使ってスキャンしても、よく使うページは追い出されない
+        let mut strategy = AccessStrategy::bulk_read();
+        for &page_id in &page_ids[4..] {
+            let buffer = bufmgr.fetch_page_with(page_id, &mut strategy).unwrap();
+            assert_eq!(page_data(page_id, 1), buffer.page.read().as_ref());
+        }
+        let misses = bufmgr.stats().misses;
+        for &page_id in hot {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(misses, bufmgr.stats().misses);
+
+        // リングを使わないスキャンでは追い出される
+        for &page_id in &page_ids[4..] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        let misses = bufmgr.stats().misses;
+        for &page_id in hot {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert!(bufmgr.stats().misses > misses);
+    }
+
+    #[test]
+    fn test_resize() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let mut pages: Vec<_> = (0..2).map(|_| bufmgr.create_page().unwrap()).collect();
+        assert!(bufmgr.create_page().is_err());
+        // 増やしたバッファはすぐに使える
+        bufmgr.resize(4).unwrap();
+        assert_eq!(4, bufmgr.pool_size());
+        pages.extend((0..2).map(|_| bufmgr.create_page().unwrap()));
+        for buffer in &pages {
+            let data = page_data(buffer.page_id, 1);
+            buffer.page.write().copy_from_slice(&data);
+        }
+        let page_ids: Vec<_> = pages.iter().map(|buffer| buffer.page_id).collect();
+
+        // すべて貸出中なら、追い出せるバッファがない
+        assert!(matches!(bufmgr.resize(1), Err(Error::NoFreeBuffer)));
+        assert_eq!(4, bufmgr.pool_size());
+        // 前のバッファを追い出し、貸出中の後ろのバッファはそこへ移す
+        let pinned = pages.split_off(2);
+        drop(pages);
+        bufmgr.resize(2).unwrap();
+        assert_eq!(2, bufmgr.pool_size());
+        for buffer in &pinned {
+            let fetched = bufmgr.fetch_page(buffer.page_id).unwrap();
+            assert!(Arc::ptr_eq(buffer, &fetched));
+        }
+        drop(pinned);
+        bufmgr.resize(1).unwrap();
+        let stats = bufmgr.stats();
+        assert_eq!((1, 1), (stats.pool_size, stats.resident_pages));
+        // 取り除いたバッファのページは書き戻されている
+        for &page_id in &page_ids {
+            let page = bufmgr.fetch_page_read(page_id).unwrap();
+            assert_eq!(page_data(page_id, 1)[PAGE_HEADER_SIZE..], page[..]);
+        }
+
+        // メモリの上限を超える大きさにはできない
+        let options = BufferPoolOptions {
+            memory_budget: Some(PAGE_SIZE * 3),
+            ..Default::default()
+        };
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new_with(disk, BufferPool::new(8), options).unwrap();
+        assert_eq!(3, bufmgr.pool_size());
+        assert!(matches!(
+            bufmgr.resize(4),
+            Err(Error::MemoryBudgetExceeded { .. })
+        ));
+        bufmgr.resize(2).unwrap();
+        // 一つのページも置けない上限では作れない
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let options = BufferPoolOptions {
+            memory_budget: Some(disk.data_size() - 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            BufferPoolManager::new_with(disk, BufferPool::new(8), options),
+            Err(Error::MemoryBudgetExceeded { pool_size: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_wal_rule() {
+        // 永続化したLSNを覚えておき、失敗させることもできるWAL
+        #[derive(Default)]
+        struct Log {
+            durable: Mutex<Lsn>,
+            fail: std::sync::atomic::AtomicBool,
+        }
+        impl WriteAheadLog for Log {
+            fn flush_to(&self, lsn: Lsn) -> std::io::Result<()> {
+                if self.fail.load(Ordering::SeqCst) {
+                    return Err(std::io::Error::other("log device is gone"));
+                }
+                let mut durable = self.durable.lock();
+                *durable = (*durable).max(lsn);
+                Ok(())
+            }
+        }
+
+        let log = Arc::new(Log::default());
+        let options = BufferPoolOptions {
+            wal: Some(log.clone()),
+            ..Default::default()
+        };
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new_with(disk, BufferPool::new(1), options).unwrap();
+        let page_id = {
+            let mut page = bufmgr.create_page_write().unwrap();
+            page.set_lsn(Lsn(10));
+            page.page_id()
+        };
+        // 追い出すときは、ページのLSNまでログが永続化されてから書き戻される
+        bufmgr.create_page().unwrap();
+        assert_eq!(Lsn(10), *log.durable.lock());
+
+        // ログを永続化できなければ、ページは書き戻されずに残る
+        {
+            let mut page = bufmgr.fetch_page_write(page_id).unwrap();
+            page.set_lsn(Lsn(20));
+            // ガードから見えるのはヘッダの後ろだけなので、本体を書き換えてもLSNは変わらない
+            page.fill(0xFF);
+            assert_eq!(Lsn(20), page.lsn());
+        }
+        log.fail.store(true, Ordering::SeqCst);
+        assert!(bufmgr.flush().is_err());
+        assert!(bufmgr.create_page().is_err());
+        assert!(bufmgr
+            .fetch_page(page_id)
+            .unwrap()
+            .is_dirty
+            .load(Ordering::SeqCst));
+
+        log.fail.store(false, Ordering::SeqCst);
+        bufmgr.flush().unwrap();
+        assert_eq!(Lsn(20), *log.durable.lock());
+        assert_eq!(Lsn(20), bufmgr.fetch_page_read(page_id).unwrap().lsn());
+    }
+
+    // ログの永続化を止めておけるWAL
+    struct BlockingLog {
+        entered: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl WriteAheadLog for BlockingLog {
+        fn flush_to(&self, _lsn: Lsn) -> std::io::Result<()> {
+            self.entered.lock().send(()).unwrap();
+            self.release.lock().recv().unwrap();
+            Ok(())
+        }
+    }
+
+    /*
+        ログの永続化で止まるWALを使うバッファプールを作る。
+        止まったことを受け取るReceiverと、止まっているflush_toを進めるSenderも返す
+    */
+    fn blocking_bufmgr(
+        pool_size: usize,
+    ) -> (Arc<BufferPoolManager>, mpsc::Receiver<()>, mpsc::Sender<()>) {
+        let (entered_tx, entered) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let log = Arc::new(BlockingLog {
+            entered: Mutex::new(entered_tx),
+            release: Mutex::new(release_rx),
+        });
+        let options = BufferPoolOptions {
+            wal: Some(log),
+            ..Default::default()
+        };
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr =
+            BufferPoolManager::new_with(disk, BufferPool::new(pool_size), options).unwrap();
+        (Arc::new(bufmgr), entered, release)
+    }
+
+    #[test]
+    fn test_io_outside_state() {
+        let (bufmgr, entered, release) = blocking_bufmgr(2);
+        let dirty_page_id = bufmgr.create_page().unwrap().page_id;
+        let pinned = bufmgr.create_page().unwrap();
+
+        // 変更されたページを追い出し、書き戻す前のログの永続化で止めておく
+        let handle = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || bufmgr.create_page().map(|buffer| buffer.page_id))
+        };
+        entered.recv_timeout(Duration::from_secs(10)).unwrap();
+        // 書き戻している間も、他のページは借りられる
+        let buffer = bufmgr.fetch_page(pinned.page_id).unwrap();
+        assert_eq!(pinned.page_id, buffer.page_id);
+        // 書き戻し終わるまでは、追い出したページもpage_tableに残っている
+        assert_eq!(2, bufmgr.stats().resident_pages);
+        release.send(()).unwrap();
+        let page_id = handle.join().unwrap().unwrap();
+        assert_ne!(dirty_page_id, page_id);
+        assert_eq!(2, bufmgr.stats().resident_pages);
+    }
+
+    #[test]
+    fn test_write_dirty_pages_without_pinning() {
+        let (bufmgr, entered, release) = blocking_bufmgr(2);
+        for _ in 0..2 {
+            bufmgr.create_page().unwrap();
+        }
+        // バックグラウンドの書き戻しを、ログの永続化で止めておく
+        let writer = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || bufmgr.write_dirty_pages(2))
+        };
+        entered.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(0, bufmgr.snapshot().stats.pinned_frames);
+        // 書き戻しはラッチを持っているので、ラッチのArcはバッファとガードとここの3つから参照される
+        let latch = Arc::clone(&bufmgr.state.lock().pool[BufferId(0)].buffer.page);
+        assert_eq!(3, Arc::strong_count(&latch));
+        // どのバッファも書き戻し中でも、書き戻しが終わるのを待って追い出せる
+        let reader = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || bufmgr.create_page().map(|buffer| buffer.page_id))
+        };
+        // 追い出す側がラッチを複製して待ち始めるまで進める
+        while Arc::strong_count(&latch) < 4 {
+            std::thread::yield_now();
+        }
+        drop(latch);
+        release.send(()).unwrap();
+        assert_eq!(2, writer.join().unwrap().unwrap());
+        // 書き戻したページは変更されていないので、追い出すときにログを待たない
+        reader.join().unwrap().unwrap();
+        assert_eq!(0, bufmgr.stats().no_free_buffer);
+    }
+
+    #[test]
+    fn test_flush_with_latched_page() {
+        let (bufmgr, entered, release) = blocking_bufmgr(2);
+        let low = bufmgr.create_page().unwrap().page_id;
+        let high = bufmgr.create_page().unwrap().page_id;
+        // 後ろのページのガードを持ったまま、前のページを借りようとするスレッドがあってもflushは止まらない
+        let high_page = bufmgr.fetch_page_write(high).unwrap();
+        let flusher = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || bufmgr.flush())
+        };
+        // 取れたラッチの分だけを先に書き戻す
+        entered.recv_timeout(Duration::from_secs(10)).unwrap();
+        release.send(()).unwrap();
+        let mut low_page = bufmgr.fetch_page_write(low).unwrap();
+        low_page.fill(1);
+        drop((low_page, high_page));
+        entered.recv_timeout(Duration::from_secs(10)).unwrap();
+        release.send(()).unwrap();
+        flusher.join().unwrap().unwrap();
+        // ガードで書き換えたページは、書き戻した後も変更されたまま残る
+        assert!(bufmgr
+            .fetch_page(low)
+            .unwrap()
+            .is_dirty
+            .load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_resize_while_creating_page() {
+        let (bufmgr, entered, release) = blocking_bufmgr(3);
+        let pinned = bufmgr.create_page().unwrap();
+        let deleted = bufmgr.create_page().unwrap();
+        bufmgr.create_page().unwrap();
+        // 最後のバッファの変更されたページを追い出し、書き戻す前のログの永続化で止めておく
+        let creator = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || bufmgr.create_page().map(|buffer| buffer.page_id))
+        };
+        entered.recv_timeout(Duration::from_secs(10)).unwrap();
+        let latch = Arc::clone(&bufmgr.state.lock().pool[BufferId(2)].buffer.page);
+        let deleted_id = deleted.page_id;
+        drop(deleted);
+        bufmgr.delete_page(deleted_id).unwrap();
+
+        // 作っている途中のバッファは取り除かずに、終わるのを待ってから前のバッファへ移す
+        let resizer = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || bufmgr.resize(2))
+        };
+        // resizeが待つためにラッチを複製するまで進める
+        while Arc::strong_count(&latch) < 3 && !resizer.is_finished() {
+            std::thread::yield_now();
+        }
+        assert!(!resizer.is_finished());
+        drop(latch);
+        release.send(()).unwrap();
+        let page_id = creator.join().unwrap().unwrap();
+        resizer.join().unwrap().unwrap();
+        assert_eq!(2, bufmgr.pool_size());
+        assert_eq!(page_id, bufmgr.fetch_page(page_id).unwrap().page_id);
+        assert!(Arc::ptr_eq(
+            &pinned,
+            &bufmgr.fetch_page(pinned.page_id).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_reuse_prefetched_free_page() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(8));
+        let page_ids: Vec<_> = (0..6)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        assert_eq!(PageId(3), page_ids[2]);
+        bufmgr.flush().unwrap();
+        bufmgr.delete_page(PageId(3)).unwrap();
+        // 順に読むと続くページが先読みされるが、解放したページは読み込まない
+        for page_id in [PageId(1), PageId(2)] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(0, bufmgr.prefetch(&[PageId(3)]));
+        assert_eq!(0, bufmgr.stats().prefetched_pages);
+        {
+            let mut page = bufmgr.create_page_write().unwrap();
+            assert_eq!(PageId(3), page.page_id());
+            page.fill(0xAB);
+        }
+        bufmgr.create_page().unwrap();
+        let page = bufmgr.fetch_page_read(PageId(3)).unwrap();
+        assert!(page.iter().all(|&b| b == 0xAB));
+    }
 }
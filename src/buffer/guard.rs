@@ -0,0 +1,93 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, RawRwLock};
+
+use super::buffer::{Buffer, Page};
+use super::wal::{page_lsn, set_page_lsn, Lsn, PAGE_HEADER_SIZE};
+use crate::disk::PageId;
+
+/*
+    読み込みラッチを持ったままバッファを貸し出すガード。
+    ガードがArc<Buffer>を持っている間はページが貸出中になり、追い出されない。
+    ガードを捨てると、ラッチを外してから貸出を終える。
+    ページの先頭のヘッダはバッファプールが書き戻すときに使うので、ガードからはヘッダの後ろの本体だけが見え、LSNはlsnで読む。
+*/
+pub struct PageReadGuard {
+    // フィールドは宣言した順に捨てられるので、ラッチをbufferより先に置く
+    page: ArcRwLockReadGuard<RawRwLock, Page>,
+    buffer: Arc<Buffer>,
+}
+
+impl PageReadGuard {
+    pub(crate) fn new(buffer: Arc<Buffer>) -> Self {
+        Self {
+            page: buffer.page.read_arc(),
+            buffer,
+        }
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.buffer.page_id
+    }
+
+    // ページのヘッダに書かれているLSN
+    pub fn lsn(&self) -> Lsn {
+        page_lsn(&self.page)
+    }
+}
+
+impl Deref for PageReadGuard {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.page[PAGE_HEADER_SIZE..]
+    }
+}
+
+/*
+    書き込みラッチを持ったままバッファを貸し出すガード。
+    ページを書き換えるために借りたときに、ラッチを持ったままis_dirtyを立てるので、呼び出し側で立て忘れることがない。
+    本体を書き換えたら、その変更を記録したログレコードのLSNをset_lsnで書いておく。
+*/
+pub struct PageWriteGuard {
+    page: ArcRwLockWriteGuard<RawRwLock, Page>,
+    buffer: Arc<Buffer>,
+}
+
+impl PageWriteGuard {
+    pub(crate) fn new(buffer: Arc<Buffer>) -> Self {
+        Self {
+            page: buffer.page.write_arc(),
+            buffer,
+        }
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.buffer.page_id
+    }
+
+    // ページのヘッダに書かれているLSN
+    pub fn lsn(&self) -> Lsn {
+        page_lsn(&self.page)
+    }
+
+    pub fn set_lsn(&mut self, lsn: Lsn) {
+        self.buffer.is_dirty.store(true, Ordering::SeqCst);
+        set_page_lsn(&mut self.page, lsn);
+    }
+}
+
+impl Deref for PageWriteGuard {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.page[PAGE_HEADER_SIZE..]
+    }
+}
+
+impl DerefMut for PageWriteGuard {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.is_dirty.store(true, Ordering::SeqCst);
+        &mut self.page[PAGE_HEADER_SIZE..]
+    }
+}
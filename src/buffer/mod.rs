@@ -1,4 +1,23 @@
 mod buffer;
 mod buffer_pool_manager;
+mod guard;
+mod policy;
+mod stats;
+mod strategy;
+mod wal;
+mod warm_up;
+mod writer;
 
-pub use crate::buffer::buffer_pool_manager::BufferPoolManager;
+pub use crate::buffer::buffer::{BufferId, BufferPool};
+pub use crate::buffer::buffer_pool_manager::{BufferPoolManager, BufferPoolOptions};
+pub use crate::buffer::guard::{PageReadGuard, PageWriteGuard};
+pub use crate::buffer::policy::{
+    AdaptiveReplacement, ClockSweep, EvictionPolicy, Lru, LruK, Policy, TwoQueue,
+};
+pub use crate::buffer::stats::{BufferPoolSnapshot, BufferPoolStats, ResidentPage};
+pub use crate::buffer::strategy::AccessStrategy;
+pub use crate::buffer::wal::{
+    page_lsn, set_page_lsn, Lsn, PageHeader, WriteAheadLog, PAGE_HEADER_SIZE,
+};
+pub use crate::buffer::warm_up::WarmUp;
+pub use crate::buffer::writer::{BackgroundWriter, BackgroundWriterOptions};
@@ -0,0 +1,851 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use super::buffer::BufferId;
+use crate::disk::PageId;
+
+/*
+    バッファプールが満杯のときに、どのバッファのページを追い出すかを決めるトレイト。
+    BufferPoolはページの読み込みや再利用をこのトレイトに知らせ、追い出すバッファをvictimで選んでもらう。
+    作ったばかりのバッファはどのページも持っておらず、最初に追い出されるものとして扱う。
+*/
+pub trait EvictionPolicy: Send {
+    // page_idのページをbuffer_idのバッファに読み込んだ。それまで入っていたページは追い出されている
+    fn on_load(&mut self, buffer_id: BufferId, page_id: PageId);
+    // buffer_idのバッファに入っているページが再び使われた
+    fn on_hit(&mut self, buffer_id: BufferId);
+    // buffer_idのバッファが空になった(ページの削除や読み込みの失敗)。次に追い出されるようにする
+    fn on_remove(&mut self, buffer_id: BufferId);
+    // 追い出すバッファを選ぶ。貸出中(is_pinnedがtrue)のバッファは選ばない。すべて貸出中ならNone
+    fn victim(&mut self, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId>;
+    /*
+        buffer_idのバッファに入っているページが、読み込まれてから使われた回数の目安。空のバッファは0。
+        ClockSweepでは、追い出すバッファを探して巡回するたびに減っていく
+    */
+    fn usage_count(&self, buffer_id: BufferId) -> u64;
+    /*
+        追い出される順に並べたすべてのバッファ。貸出中のものも含む。
+        バックグラウンドの書き戻しが、次に追い出されそうなページから書き戻すために使う
+    */
+    fn eviction_order(&self) -> Vec<BufferId>;
+    /*
+        バッファの数をpool_sizeに変える。増えたバッファは空のバッファとして扱う。
+        減らす場合は、pool_size以降のバッファを空にしてから呼ばれる
+    */
+    fn resize(&mut self, pool_size: usize);
+}
+
+// バッファプールを作るときに選ぶ追い出しの方針
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    ClockSweep,
+    Lru,
+    // 最近K回の使用のうち、最も古い使用が一番昔のページを追い出す
+    LruK(usize),
+    TwoQueue,
+    AdaptiveReplacement,
+}
+
+impl Policy {
+    pub fn build(self, pool_size: usize) -> Box<dyn EvictionPolicy> {
+        match self {
+            Policy::ClockSweep => Box::new(ClockSweep::new(pool_size)),
+            Policy::Lru => Box::new(Lru::new(pool_size)),
+            Policy::LruK(k) => Box::new(LruK::new(pool_size, k)),
+            Policy::TwoQueue => Box::new(TwoQueue::new(pool_size)),
+            Policy::AdaptiveReplacement => Box::new(AdaptiveReplacement::new(pool_size)),
+        }
+    }
+}
+
+/*
+    Clock-sweepアルゴリズムは、特定の条件を満たすフレームを置き換えるために使用されます。
+    置き換えるフレームを選択するために、clock-sweepアルゴリズムは単純なカウンタを使用し、
+    バッファプール内のすべてのフレームを周回します。
+    フレームが使用されていない場合、または使用回数が低い場合は、そのフレームを置き換えることができます。
+    使用回数が高いフレームは、しばらく使用され続ける可能性が高いため、置き換えるのが難しいとされます。
+    clock-sweepアルゴリズムは、バッファプールが大きくなるにつれて、
+    時間がかかる傾向があるため、大規模なシステムでは使用しない方が良い場合もあります。
+*/
+pub struct ClockSweep {
+    // bufferの使用回数。多いほどクリアされづらくなる
+    usage_counts: Vec<u64>,
+    next_victim_id: usize,
+}
+
+impl ClockSweep {
+    pub fn new(pool_size: usize) -> Self {
+        Self {
+            usage_counts: vec![0; pool_size],
+            next_victim_id: 0,
+        }
+    }
+}
+
+impl EvictionPolicy for ClockSweep {
+    fn on_load(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.usage_counts[buffer_id.0] = 1;
+    }
+
+    fn on_hit(&mut self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.0] += 1;
+    }
+
+    fn on_remove(&mut self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.0] = 0;
+    }
+
+    fn victim(&mut self, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let pool_size = self.usage_counts.len();
+        if pool_size == 0 {
+            return None;
+        }
+        let mut consecutive_pinned = 0;
+        // bufferpoolの全てのbufferを巡回しながら捨てるものを決める
+        loop {
+            let buffer_id = BufferId(self.next_victim_id);
+            if is_pinned(buffer_id) {
+                /*
+                    貸出中だった場合はconsective_pinnedカウンタを増やす。
+                    カウンタがbuffer_poolと同じになった場合にはすべてのbufferが貸出中ということなので、
+                    諦めてNoneを返す
+                */
+                consecutive_pinned += 1;
+                if consecutive_pinned >= pool_size {
+                    return None;
+                }
+            } else {
+                let usage_count = &mut self.usage_counts[buffer_id.0];
+                if *usage_count == 0 {
+                    return Some(buffer_id);
+                }
+                // 巡回中に貸出中でなければデクリメントされる
+                *usage_count -= 1;
+                consecutive_pinned = 0;
+            }
+            self.next_victim_id = (self.next_victim_id + 1) % pool_size;
+        }
+    }
+
+    fn usage_count(&self, buffer_id: BufferId) -> u64 {
+        self.usage_counts[buffer_id.0]
+    }
+
+    // 使用回数の少ない順に、同じならclock-sweepが次に巡回する順に並べる
+    fn eviction_order(&self) -> Vec<BufferId> {
+        let pool_size = self.usage_counts.len();
+        let mut order: Vec<_> = (0..pool_size)
+            .map(|i| BufferId((self.next_victim_id + i) % pool_size))
+            .collect();
+        order.sort_by_key(|buffer_id| self.usage_counts[buffer_id.0]);
+        order
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        self.usage_counts.resize(pool_size, 0);
+        if self.next_victim_id >= pool_size {
+            self.next_victim_id = 0;
+        }
+    }
+}
+
+// 最後に使われたのが一番昔のページを追い出す
+pub struct Lru {
+    // 最後に使われたのが古い順に並べたバッファ。空のバッファは先頭に置く
+    queue: BufferList,
+    usage_counts: Vec<u64>,
+}
+
+impl Lru {
+    pub fn new(pool_size: usize) -> Self {
+        let mut lru = Self {
+            queue: BufferList::new(pool_size),
+            usage_counts: vec![0; pool_size],
+        };
+        lru.push_empty_buffers(0);
+        lru
+    }
+
+    // old_size以降のバッファを、空のバッファとして番号の小さい順に先頭へ置く
+    fn push_empty_buffers(&mut self, old_size: usize) {
+        for buffer_id in (old_size..self.usage_counts.len()).rev() {
+            self.queue.push_front(BufferId(buffer_id));
+        }
+    }
+
+    fn touch(&mut self, buffer_id: BufferId) {
+        self.queue.remove(buffer_id);
+        self.queue.push_back(buffer_id);
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn on_load(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.usage_counts[buffer_id.0] = 1;
+        self.touch(buffer_id);
+    }
+
+    fn on_hit(&mut self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.0] += 1;
+        self.touch(buffer_id);
+    }
+
+    fn on_remove(&mut self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.0] = 0;
+        self.queue.remove(buffer_id);
+        self.queue.push_front(buffer_id);
+    }
+
+    fn victim(&mut self, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        first_unpinned(&self.queue, is_pinned)
+    }
+
+    fn usage_count(&self, buffer_id: BufferId) -> u64 {
+        self.usage_counts[buffer_id.0]
+    }
+
+    fn eviction_order(&self) -> Vec<BufferId> {
+        self.queue.iter().collect()
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        let old_size = self.usage_counts.len();
+        self.queue.resize(pool_size);
+        self.usage_counts.resize(pool_size, 0);
+        self.push_empty_buffers(old_size);
+    }
+}
+
+/*
+    LRU-K。最近K回の使用時刻を覚えておき、K回前の使用が一番昔のページを追い出す。
+    まだK回使われていないページは、K回前の使用が無限に昔にあったものとして先に追い出す。
+    一度しか使われないページが、何度も使われるページを追い出してしまうのを防げる。
+    追い出したページの使用時刻もバッファの数だけ覚えておき、すぐに読み直されたときに引き継ぐ。
+*/
+pub struct LruK {
+    k: usize,
+    history: Vec<VecDeque<u64>>,
+    pages: Vec<Option<PageId>>,
+    // 追い出す順に並べたバッファ。keyで求めたキーを使う
+    order: BTreeSet<(bool, u64, usize)>,
+    usage_counts: Vec<u64>,
+    // 追い出したページの使用時刻
+    retained: HashMap<PageId, VecDeque<u64>>,
+    // 追い出したページを、最後の使用が古い順に並べたもの
+    retained_order: BTreeSet<(u64, u64)>,
+    clock: u64,
+}
+
+impl LruK {
+    pub fn new(pool_size: usize, k: usize) -> Self {
+        let mut lru_k = Self {
+            k: k.max(1),
+            history: vec![VecDeque::new(); pool_size],
+            pages: vec![None; pool_size],
+            order: BTreeSet::new(),
+            usage_counts: vec![0; pool_size],
+            retained: HashMap::new(),
+            retained_order: BTreeSet::new(),
+            clock: 0,
+        };
+        for index in 0..pool_size {
+            lru_k.order.insert(lru_k.key(index));
+        }
+        lru_k
+    }
+
+    // 追い出す順番のキー。K回使われていないページ同士では、最後の使用が古いものを先に追い出す
+    fn key(&self, index: usize) -> (bool, u64, usize) {
+        let history = &self.history[index];
+        if history.len() < self.k {
+            (false, history.back().copied().unwrap_or(0), index)
+        } else {
+            (true, history.front().copied().unwrap_or(0), index)
+        }
+    }
+
+    fn touch(&mut self, buffer_id: BufferId) {
+        self.clock += 1;
+        let history = &mut self.history[buffer_id.0];
+        history.push_back(self.clock);
+        if history.len() > self.k {
+            history.pop_front();
+        }
+    }
+
+    fn retain(&mut self, page_id: PageId, history: VecDeque<u64>) {
+        self.take_retained(page_id);
+        let last_used = history.back().copied().unwrap_or(0);
+        self.retained.insert(page_id, history);
+        self.retained_order.insert((last_used, page_id.to_u64()));
+        // 覚えておくページが多すぎれば、最後の使用が一番昔のものから忘れる
+        if self.retained.len() > self.pages.len() {
+            if let Some((_, oldest)) = self.retained_order.pop_first() {
+                self.retained.remove(&PageId(oldest));
+            }
+        }
+    }
+
+    // 追い出したときに覚えておいたpage_idの使用時刻を取り出す
+    fn take_retained(&mut self, page_id: PageId) -> Option<VecDeque<u64>> {
+        let history = self.retained.remove(&page_id)?;
+        let last_used = history.back().copied().unwrap_or(0);
+        self.retained_order.remove(&(last_used, page_id.to_u64()));
+        Some(history)
+    }
+}
+
+impl EvictionPolicy for LruK {
+    fn on_load(&mut self, buffer_id: BufferId, page_id: PageId) {
+        self.order.remove(&self.key(buffer_id.0));
+        let history = std::mem::take(&mut self.history[buffer_id.0]);
+        if let Some(old_page_id) = self.pages[buffer_id.0].replace(page_id) {
+            self.retain(old_page_id, history);
+        }
+        self.history[buffer_id.0] = self.take_retained(page_id).unwrap_or_default();
+        self.touch(buffer_id);
+        self.order.insert(self.key(buffer_id.0));
+        self.usage_counts[buffer_id.0] = 1;
+    }
+
+    fn on_hit(&mut self, buffer_id: BufferId) {
+        self.order.remove(&self.key(buffer_id.0));
+        self.touch(buffer_id);
+        self.order.insert(self.key(buffer_id.0));
+        self.usage_counts[buffer_id.0] += 1;
+    }
+
+    fn on_remove(&mut self, buffer_id: BufferId) {
+        self.order.remove(&self.key(buffer_id.0));
+        self.history[buffer_id.0].clear();
+        self.order.insert(self.key(buffer_id.0));
+        self.pages[buffer_id.0] = None;
+        self.usage_counts[buffer_id.0] = 0;
+    }
+
+    fn victim(&mut self, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        self.order
+            .iter()
+            .map(|&(_, _, index)| BufferId(index))
+            .find(|&buffer_id| !is_pinned(buffer_id))
+    }
+
+    fn usage_count(&self, buffer_id: BufferId) -> u64 {
+        self.usage_counts[buffer_id.0]
+    }
+
+    fn eviction_order(&self) -> Vec<BufferId> {
+        self.order
+            .iter()
+            .map(|&(_, _, index)| BufferId(index))
+            .collect()
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        let old_size = self.history.len();
+        for index in pool_size..old_size {
+            self.order.remove(&self.key(index));
+        }
+        self.history.resize(pool_size, VecDeque::new());
+        self.pages.resize(pool_size, None);
+        self.usage_counts.resize(pool_size, 0);
+        for index in old_size..pool_size {
+            self.order.insert(self.key(index));
+        }
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+/*
+    バッファを並べた双方向の連結リスト。
+    前後のバッファをバッファIDを添字にした配列で持つので、どのバッファも定数時間で取り除いたり末尾に移したりできる。
+*/
+struct BufferList {
+    prev: Vec<usize>,
+    next: Vec<usize>,
+    linked: Vec<bool>,
+    head: usize,
+    tail: usize,
+    len: usize,
+}
+
+impl BufferList {
+    fn new(pool_size: usize) -> Self {
+        Self {
+            prev: vec![NIL; pool_size],
+            next: vec![NIL; pool_size],
+            linked: vec![false; pool_size],
+            head: NIL,
+            tail: NIL,
+            len: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push_back(&mut self, buffer_id: BufferId) {
+        let index = buffer_id.0;
+        debug_assert!(!self.linked[index]);
+        self.prev[index] = self.tail;
+        self.next[index] = NIL;
+        match self.tail {
+            NIL => self.head = index,
+            tail => self.next[tail] = index,
+        }
+        self.tail = index;
+        self.linked[index] = true;
+        self.len += 1;
+    }
+
+    fn push_front(&mut self, buffer_id: BufferId) {
+        let index = buffer_id.0;
+        debug_assert!(!self.linked[index]);
+        self.prev[index] = NIL;
+        self.next[index] = self.head;
+        match self.head {
+            NIL => self.tail = index,
+            head => self.prev[head] = index,
+        }
+        self.head = index;
+        self.linked[index] = true;
+        self.len += 1;
+    }
+
+    // buffer_idをリストから取り除く。入っていればtrueを返す
+    fn remove(&mut self, buffer_id: BufferId) -> bool {
+        let index = buffer_id.0;
+        if !self.linked.get(index).copied().unwrap_or(false) {
+            return false;
+        }
+        let (prev, next) = (self.prev[index], self.next[index]);
+        match prev {
+            NIL => self.head = next,
+            prev => self.next[prev] = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.prev[next] = prev,
+        }
+        self.linked[index] = false;
+        self.len -= 1;
+        true
+    }
+
+    // 先頭から順にバッファを返す
+    fn iter(&self) -> impl Iterator<Item = BufferId> + '_ {
+        let head = (self.head != NIL).then_some(self.head);
+        std::iter::successors(head, |&index| {
+            let next = self.next[index];
+            (next != NIL).then_some(next)
+        })
+        .map(BufferId)
+    }
+
+    // pool_size以降のバッファを取り除き、pool_sizeまでのバッファを入れられるようにする
+    fn resize(&mut self, pool_size: usize) {
+        for index in pool_size..self.linked.len() {
+            self.remove(BufferId(index));
+        }
+        self.prev.resize(pool_size, NIL);
+        self.next.resize(pool_size, NIL);
+        self.linked.resize(pool_size, false);
+    }
+}
+
+/*
+    追い出したページのIDを古い順に覚えておくリスト。
+    ページIDから覚えた順番を引けるようにしておくので、どのページも対数時間で忘れられる。
+*/
+#[derive(Default)]
+struct GhostList {
+    seqs: HashMap<PageId, u64>,
+    pages: BTreeMap<u64, PageId>,
+    next_seq: u64,
+}
+
+impl GhostList {
+    fn len(&self) -> usize {
+        self.seqs.len()
+    }
+
+    fn push_back(&mut self, page_id: PageId) {
+        self.remove(page_id);
+        self.seqs.insert(page_id, self.next_seq);
+        self.pages.insert(self.next_seq, page_id);
+        self.next_seq += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<PageId> {
+        let (_, page_id) = self.pages.pop_first()?;
+        self.seqs.remove(&page_id);
+        Some(page_id)
+    }
+
+    // page_idを忘れる。覚えていればtrueを返す
+    fn remove(&mut self, page_id: PageId) -> bool {
+        match self.seqs.remove(&page_id) {
+            Some(seq) => {
+                self.pages.remove(&seq);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// リストの先頭から、貸出中でない最初のバッファを探す
+fn first_unpinned(list: &BufferList, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+    list.iter().find(|&buffer_id| !is_pinned(buffer_id))
+}
+
+/*
+    2Q。初めて使われたページはFIFOのA1inに入れ、A1inから追い出したページのIDをA1outに覚えておく。
+    A1outに覚えているうちにもう一度使われたページだけを、LRUで管理するAmに入れる。
+    一度きりのスキャンで読まれたページはA1inの中で入れ替わるだけなので、Amのページを追い出さない。
+*/
+pub struct TwoQueue {
+    a1in: BufferList,
+    am: BufferList,
+    a1out: GhostList,
+    pages: Vec<Option<PageId>>,
+    usage_counts: Vec<u64>,
+    // A1inに置くバッファの数の目安
+    kin: usize,
+    // A1outに覚えておくページの数
+    kout: usize,
+}
+
+impl TwoQueue {
+    pub fn new(pool_size: usize) -> Self {
+        let mut a1in = BufferList::new(pool_size);
+        for buffer_id in 0..pool_size {
+            a1in.push_back(BufferId(buffer_id));
+        }
+        Self {
+            a1in,
+            am: BufferList::new(pool_size),
+            a1out: GhostList::default(),
+            pages: vec![None; pool_size],
+            usage_counts: vec![0; pool_size],
+            kin: (pool_size / 4).max(1),
+            kout: (pool_size / 2).max(1),
+        }
+    }
+}
+
+impl EvictionPolicy for TwoQueue {
+    fn on_load(&mut self, buffer_id: BufferId, page_id: PageId) {
+        let was_in_a1in = self.a1in.remove(buffer_id);
+        self.am.remove(buffer_id);
+        if let Some(old_page_id) = self.pages[buffer_id.0].replace(page_id) {
+            if was_in_a1in {
+                self.a1out.push_back(old_page_id);
+                if self.a1out.len() > self.kout {
+                    self.a1out.pop_front();
+                }
+            }
+        }
+        if self.a1out.remove(page_id) {
+            self.am.push_back(buffer_id);
+        } else {
+            self.a1in.push_back(buffer_id);
+        }
+        self.usage_counts[buffer_id.0] = 1;
+    }
+
+    fn on_hit(&mut self, buffer_id: BufferId) {
+        if self.am.remove(buffer_id) {
+            self.am.push_back(buffer_id);
+        }
+        self.usage_counts[buffer_id.0] += 1;
+    }
+
+    fn on_remove(&mut self, buffer_id: BufferId) {
+        self.a1in.remove(buffer_id);
+        self.am.remove(buffer_id);
+        self.pages[buffer_id.0] = None;
+        self.usage_counts[buffer_id.0] = 0;
+        self.a1in.push_front(buffer_id);
+    }
+
+    fn victim(&mut self, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        if self.a1in.len() > self.kin {
+            first_unpinned(&self.a1in, is_pinned).or_else(|| first_unpinned(&self.am, is_pinned))
+        } else {
+            first_unpinned(&self.am, is_pinned).or_else(|| first_unpinned(&self.a1in, is_pinned))
+        }
+    }
+
+    fn usage_count(&self, buffer_id: BufferId) -> u64 {
+        self.usage_counts[buffer_id.0]
+    }
+
+    fn eviction_order(&self) -> Vec<BufferId> {
+        let (first, second) = if self.a1in.len() > self.kin {
+            (&self.a1in, &self.am)
+        } else {
+            (&self.am, &self.a1in)
+        };
+        first.iter().chain(second.iter()).collect()
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        let old_size = self.pages.len();
+        self.a1in.resize(pool_size);
+        self.am.resize(pool_size);
+        for buffer_id in old_size..pool_size {
+            self.a1in.push_front(BufferId(buffer_id));
+        }
+        self.pages.resize(pool_size, None);
+        self.usage_counts.resize(pool_size, 0);
+        self.kin = (pool_size / 4).max(1);
+        self.kout = (pool_size / 2).max(1);
+        while self.a1out.len() > self.kout && self.a1out.pop_front().is_some() {}
+    }
+}
+
+/*
+    ARC(Adaptive Replacement Cache)。
+    一度だけ使われたページのLRU(T1)と、二度以上使われたページのLRU(T2)を持ち、
+    それぞれから追い出したページのIDをB1、B2に覚えておく。
+    B1に覚えているページが読み直されたらT1を、B2なら T2を大きくするように目標の大きさpを調整し、
+    アクセスの傾向に合わせて二つのLRUの割合を変えていく。
+*/
+pub struct AdaptiveReplacement {
+    t1: BufferList,
+    t2: BufferList,
+    b1: GhostList,
+    b2: GhostList,
+    pages: Vec<Option<PageId>>,
+    usage_counts: Vec<u64>,
+    // T1の目標の大きさ
+    p: usize,
+}
+
+impl AdaptiveReplacement {
+    pub fn new(pool_size: usize) -> Self {
+        let mut t1 = BufferList::new(pool_size);
+        for buffer_id in 0..pool_size {
+            t1.push_back(BufferId(buffer_id));
+        }
+        Self {
+            t1,
+            t2: BufferList::new(pool_size),
+            b1: GhostList::default(),
+            b2: GhostList::default(),
+            pages: vec![None; pool_size],
+            usage_counts: vec![0; pool_size],
+            p: 0,
+        }
+    }
+}
+
+impl EvictionPolicy for AdaptiveReplacement {
+    fn on_load(&mut self, buffer_id: BufferId, page_id: PageId) {
+        let pool_size = self.pages.len();
+        let was_in_t1 = self.t1.remove(buffer_id);
+        self.t2.remove(buffer_id);
+        if let Some(old_page_id) = self.pages[buffer_id.0].replace(page_id) {
+            if was_in_t1 {
+                self.b1.push_back(old_page_id);
+            } else {
+                self.b2.push_back(old_page_id);
+            }
+        }
+        if self.b1.remove(page_id) {
+            // T1から追い出すのが早すぎたので、T1を大きくする
+            let delta = (self.b2.len() / (self.b1.len() + 1)).max(1);
+            self.p = (self.p + delta).min(pool_size);
+            self.t2.push_back(buffer_id);
+        } else if self.b2.remove(page_id) {
+            // T2から追い出すのが早すぎたので、T2を大きくする
+            let delta = (self.b1.len() / (self.b2.len() + 1)).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.t2.push_back(buffer_id);
+        } else {
+            self.t1.push_back(buffer_id);
+        }
+        self.usage_counts[buffer_id.0] = 1;
+        // 覚えておくページのIDは、T1とB1で合わせてバッファの数まで、全体でその2倍まで
+        while self.t1.len() + self.b1.len() > pool_size && self.b1.pop_front().is_some() {}
+        let total = self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len();
+        for _ in pool_size * 2..total {
+            if self.b2.pop_front().is_none() {
+                break;
+            }
+        }
+    }
+
+    fn on_hit(&mut self, buffer_id: BufferId) {
+        if self.t1.remove(buffer_id) || self.t2.remove(buffer_id) {
+            self.t2.push_back(buffer_id);
+        }
+        self.usage_counts[buffer_id.0] += 1;
+    }
+
+    fn on_remove(&mut self, buffer_id: BufferId) {
+        self.t1.remove(buffer_id);
+        self.t2.remove(buffer_id);
+        self.pages[buffer_id.0] = None;
+        self.usage_counts[buffer_id.0] = 0;
+        self.t1.push_front(buffer_id);
+    }
+
+    fn victim(&mut self, is_pinned: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        if !self.t1.is_empty() && self.t1.len() > self.p {
+            first_unpinned(&self.t1, is_pinned).or_else(|| first_unpinned(&self.t2, is_pinned))
+        } else {
+            first_unpinned(&self.t2, is_pinned).or_else(|| first_unpinned(&self.t1, is_pinned))
+        }
+    }
+
+    fn usage_count(&self, buffer_id: BufferId) -> u64 {
+        self.usage_counts[buffer_id.0]
+    }
+
+    fn eviction_order(&self) -> Vec<BufferId> {
+        let (first, second) = if !self.t1.is_empty() && self.t1.len() > self.p {
+            (&self.t1, &self.t2)
+        } else {
+            (&self.t2, &self.t1)
+        };
+        first.iter().chain(second.iter()).collect()
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        let old_size = self.pages.len();
+        self.t1.resize(pool_size);
+        self.t2.resize(pool_size);
+        for buffer_id in old_size..pool_size {
+            self.t1.push_front(BufferId(buffer_id));
+        }
+        self.pages.resize(pool_size, None);
+        self.usage_counts.resize(pool_size, 0);
+        self.p = self.p.min(pool_size);
+        while self.t1.len() + self.b1.len() > pool_size && self.b1.pop_front().is_some() {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > pool_size * 2
+            && self.b2.pop_front().is_some()
+        {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ページIDの列を順に使い、バッファに載っていたかどうかを数える
+    fn hits(policy: Policy, pool_size: usize, trace: &[u64]) -> usize {
+        let mut policy = policy.build(pool_size);
+        let mut resident: HashMap<u64, BufferId> = HashMap::new();
+        let mut hits = 0;
+        for &page in trace {
+            if let Some(&buffer_id) = resident.get(&page) {
+                policy.on_hit(buffer_id);
+                hits += 1;
+                continue;
+            }
+            let buffer_id = policy.victim(&|_| false).unwrap();
+            resident.retain(|_, &mut id| id != buffer_id);
+            policy.on_load(buffer_id, PageId(page));
+            resident.insert(page, buffer_id);
+        }
+        hits
+    }
+
+    #[test]
+    fn test_buffer_list() {
+        let mut list = BufferList::new(4);
+        for buffer_id in [2, 0, 3] {
+            list.push_back(BufferId(buffer_id));
+        }
+        list.push_front(BufferId(1));
+        assert!(list.remove(BufferId(0)));
+        assert!(!list.remove(BufferId(0)));
+        list.push_back(BufferId(0));
+        assert_eq!(
+            vec![1, 2, 3, 0],
+            list.iter().map(|id| id.0).collect::<Vec<_>>()
+        );
+        // 縮めると後ろのバッファは取り除かれる
+        list.resize(2);
+        assert_eq!(vec![1, 0], list.iter().map(|id| id.0).collect::<Vec<_>>());
+        assert_eq!(2, list.len());
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        // 4ページを繰り返し使う合間に、一度しか使わないページを大量に読む
+        let mut trace = vec![];
+        for round in 0..20u64 {
+            trace.extend([0, 1, 2, 3, 0, 1, 2, 3]);
+            trace.extend((0..6).map(|i| 1000 + round * 6 + i));
+        }
+        let lru = hits(Policy::Lru, 8, &trace);
+        for policy in [
+            Policy::LruK(2),
+            Policy::TwoQueue,
+            Policy::AdaptiveReplacement,
+        ] {
+            assert!(hits(policy, 8, &trace) > lru, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_pinned() {
+        for policy in [
+            Policy::ClockSweep,
+            Policy::Lru,
+            Policy::LruK(2),
+            Policy::TwoQueue,
+            Policy::AdaptiveReplacement,
+        ] {
+            let mut policy = policy.build(3);
+            for i in 0..3 {
+                let buffer_id = policy.victim(&|_| false).unwrap();
+                policy.on_load(buffer_id, PageId(i));
+            }
+            // 貸出中のバッファは選ばれない
+            assert_eq!(None, policy.victim(&|_| true));
+            let victim = policy.victim(&|id| id != BufferId(1));
+            assert_eq!(Some(BufferId(1)), victim);
+            // 追い出される順は、貸出中のものを除けばvictimと同じバッファから始まる
+            let victim = policy.victim(&|_| false);
+            assert_eq!(victim, policy.eviction_order().first().copied());
+            assert_eq!(3, policy.eviction_order().len());
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        for policy in [
+            Policy::ClockSweep,
+            Policy::Lru,
+            Policy::LruK(2),
+            Policy::TwoQueue,
+            Policy::AdaptiveReplacement,
+        ] {
+            let mut policy = policy.build(4);
+            for i in 0..4 {
+                let buffer_id = policy.victim(&|_| false).unwrap();
+                policy.on_load(buffer_id, PageId(i));
+            }
+            // 取り除いたバッファは選ばれない
+            policy.resize(2);
+            for i in 4..10 {
+                let buffer_id = policy.victim(&|_| false).unwrap();
+                assert!(buffer_id.0 < 2);
+                policy.on_load(buffer_id, PageId(i));
+            }
+            policy.resize(3);
+            let buffer_id = policy.victim(&|id| id != BufferId(2)).unwrap();
+            assert_eq!(BufferId(2), buffer_id);
+        }
+    }
+}
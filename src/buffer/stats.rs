@@ -0,0 +1,71 @@
+use std::sync::atomic::AtomicU64;
+
+use super::buffer::BufferId;
+use crate::disk::PageId;
+
+// BufferPoolManagerが数えている回数。他の値と合わせて読む必要はないので、それぞれ独立に数える
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+    pub dirty_write_backs: AtomicU64,
+    pub flushed_pages: AtomicU64,
+    pub prefetched_pages: AtomicU64,
+    pub no_free_buffer: AtomicU64,
+}
+
+// バッファプールの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    // バッファの数
+    pub pool_size: usize,
+    // バッファプールに載っているページの数
+    pub resident_pages: usize,
+    // 貸出中のバッファの数
+    pub pinned_frames: usize,
+    // fetch_pageでページがバッファプールに載っていた回数
+    pub hits: u64,
+    // fetch_pageでページをディスクから読み込んだ回数
+    pub misses: u64,
+    // ページを追い出してバッファを再利用した回数
+    pub evictions: u64,
+    // 追い出すときに、変更されていたページを書き戻した回数
+    pub dirty_write_backs: u64,
+    // flushやwrite_dirty_pagesで書き戻したページの数
+    pub flushed_pages: u64,
+    // 先読みやprefetchで読み込んだページの数
+    pub prefetched_pages: u64,
+    // すべてのバッファが貸出中で、NoFreeBufferを返した回数
+    pub no_free_buffer: u64,
+}
+
+impl BufferPoolStats {
+    // fetch_pageのうち、ページがバッファプールに載っていた割合
+    pub fn hit_ratio(&self) -> f64 {
+        let fetches = self.hits + self.misses;
+        if fetches == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / fetches as f64
+    }
+}
+
+// バッファプールに載っているページ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResidentPage {
+    pub page_id: PageId,
+    pub buffer_id: BufferId,
+    // ページを読み込んでからの使用回数
+    pub usage_count: u64,
+    pub is_dirty: bool,
+    pub pinned: bool,
+}
+
+// ある時点のバッファプールの統計と、載っているページの一覧
+#[derive(Debug, Clone, Default)]
+pub struct BufferPoolSnapshot {
+    pub stats: BufferPoolStats,
+    // ページIDの順に並べる
+    pub resident_pages: Vec<ResidentPage>,
+}
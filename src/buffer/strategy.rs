@@ -0,0 +1,56 @@
+use super::buffer::BufferId;
+
+/*
+    大量のページを一度だけ読み書きする処理のための、バッファの使い方。
+    読み込むページのために共有のバッファプールから追い出すのではなく、
+    自分専用の小さなリングに並べたバッファを順に使い回すので、よく使われるページを追い出さずに済む。
+    リングのバッファが貸出中だったり、他の処理からも使われていたりした場合は、共有のバッファプールから借りて入れ替える。
+
+    一つのスキャンや一括ロードごとに作り、fetch_page_withやcreate_page_withに渡す。
+*/
+#[derive(Debug, Clone)]
+pub struct AccessStrategy {
+    // リングの大きさ。バッファの数の1/4を超える分は使わない
+    ring_size: usize,
+    ring: Vec<BufferId>,
+    // 最後に使ったリングの位置
+    current: usize,
+}
+
+impl AccessStrategy {
+    pub fn new(ring_size: usize) -> Self {
+        Self {
+            ring_size: ring_size.max(1),
+            ring: vec![],
+            current: 0,
+        }
+    }
+
+    // テーブル全体を読むような、大量の読み込み向け
+    pub fn bulk_read() -> Self {
+        Self::new(32)
+    }
+
+    // 一括ロードのような、大量の書き込み向け。書き戻しを待つことが多いので、リングを大きめにする
+    pub fn bulk_write() -> Self {
+        Self::new(128)
+    }
+
+    pub(crate) fn ring_size(&self, pool_size: usize) -> usize {
+        self.ring_size.min(pool_size / 4).max(1)
+    }
+
+    // リングの次の位置に進み、その位置のバッファを返す。まだバッファを置いていなければNone
+    pub(crate) fn advance(&mut self, pool_size: usize) -> Option<BufferId> {
+        self.current = (self.current + 1) % self.ring_size(pool_size);
+        self.ring.get(self.current).copied()
+    }
+
+    // 今の位置にbuffer_idのバッファを置く
+    pub(crate) fn put(&mut self, buffer_id: BufferId) {
+        match self.ring.get_mut(self.current) {
+            Some(slot) => *slot = buffer_id,
+            None => self.ring.push(buffer_id),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::io;
+
+use zerocopy::{AsBytes, FromBytes};
+
+// ログレコードの位置(Log Sequence Number)。大きいほど新しい
+#[derive(Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Lsn(pub u64);
+
+/*
+    すべてのページの先頭に置くヘッダ。
+    ページを書き換えたときは、その変更を記録したログレコードのLSNをlsnに書いておく。
+    バッファプールはページを書き戻す前に、このLSNまでのログが永続化されていることを確かめる。
+*/
+#[derive(Debug, Default, Copy, Clone, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct PageHeader {
+    pub lsn: Lsn,
+}
+
+// ページデータのうち、ヘッダの後ろの使ってよい部分の先頭
+pub const PAGE_HEADER_SIZE: usize = std::mem::size_of::<PageHeader>();
+
+// ページの先頭に書かれているLSNを返す
+pub fn page_lsn(page: &[u8]) -> Lsn {
+    let mut header = PageHeader::default();
+    header
+        .as_bytes_mut()
+        .copy_from_slice(&page[..PAGE_HEADER_SIZE]);
+    header.lsn
+}
+
+// ページの先頭にLSNを書く
+pub fn set_page_lsn(page: &mut [u8], lsn: Lsn) {
+    page[..PAGE_HEADER_SIZE].copy_from_slice(PageHeader { lsn }.as_bytes());
+}
+
+/*
+    先行書き込みログ(WAL)。バッファプールは変更されたページを書き戻す前にflush_toを呼び、
+    ページのLSNまでのログを先に永続化させる。こうしておけば、ディスク上のページに入っている変更は必ずログからやり直せる。
+*/
+pub trait WriteAheadLog: Send + Sync {
+    // lsnまでのログレコードを永続化する。すでに永続化されていれば何もしない
+    fn flush_to(&self, lsn: Lsn) -> io::Result<()>;
+}
@@ -0,0 +1,149 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use serde::{Deserialize, Serialize};
+
+use super::buffer::Error;
+use super::buffer_pool_manager::BufferPoolManager;
+use crate::disk::PageId;
+
+/*
+    バッファプールに載っていたページの一覧。使用回数の多い順に並べて保存する。
+    開き直したときにこの順で読み込むので、よく使われていたページから温まる。
+*/
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct WarmList {
+    /*
+        保存したデータベースの識別子とページサイズ。違うデータベースの一覧を読み込まないように確かめる。
+        ヘッダーを持たないlegacyのファイルでは、識別子は0になる
+    */
+    pub database_id: u64,
+    pub page_size: usize,
+    // ページIDと使用回数
+    pub pages: Vec<(u64, u64)>,
+}
+
+impl WarmList {
+    // 一時ファイルに書いてから置き換えるので、途中で落ちても前の一覧か新しい一覧のどちらかが残る
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let data = bincode::serialize(self).map_err(io::Error::other)?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    // 一覧を読み込む。ファイルがない場合や壊れている場合は、空の一覧として扱う
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        Ok(bincode::deserialize(&data).unwrap_or_default())
+    }
+
+    pub fn page_ids(&self) -> impl Iterator<Item = PageId> + '_ {
+        self.pages.iter().map(|&(page_id, _)| PageId(page_id))
+    }
+}
+
+/*
+    保存しておいたページの一覧を、バックグラウンドでバッファプールに読み込むスレッド。
+    空いているバッファにだけ読み込むので、先に使われ始めたページを追い出すことはない。
+*/
+pub struct WarmUp {
+    handle: JoinHandle<Result<usize, Error>>,
+}
+
+impl WarmUp {
+    pub fn start(bufmgr: Arc<BufferPoolManager>, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let handle = thread::spawn(move || bufmgr.warm_up(&path));
+        Self { handle }
+    }
+
+    // 読み込みが終わるのを待ち、読み込んだページの数を返す
+    pub fn wait(self) -> Result<usize, Error> {
+        self.handle.join().expect("warm-up thread panicked")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::{DiskManager, DiskOptions, FaultConfig, FaultyStorage};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_warm_up() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("warm");
+        let storage = FaultyStorage::new(FaultConfig::default());
+        let disk = DiskManager::with_storage(storage.clone(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(8));
+        let page_ids: Vec<_> = (0..8)
+            .map(|i| {
+                let mut page = bufmgr.create_page_write().unwrap();
+                page.fill(i);
+                page.page_id()
+            })
+            .collect();
+        // 後ろの4ページをよく使う
+        for _ in 0..3 {
+            for &page_id in &page_ids[4..] {
+                bufmgr.fetch_page(page_id).unwrap();
+            }
+        }
+        bufmgr.flush().unwrap();
+        bufmgr.save_warm_list(&path).unwrap();
+        drop(bufmgr);
+
+        // バッファが足りなければ、使用回数の多いページから読み込む
+        let disk = DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+        let bufmgr = Arc::new(BufferPoolManager::new(disk, BufferPool::new(4)));
+        assert_eq!(4, WarmUp::start(Arc::clone(&bufmgr), &path).wait().unwrap());
+        for (i, &page_id) in page_ids.iter().enumerate().skip(4) {
+            let page = bufmgr.fetch_page_read(page_id).unwrap();
+            assert!(page.iter().all(|&b| b == i as u8));
+        }
+        assert_eq!(0, bufmgr.stats().misses);
+
+        // 古くなった一覧や壊れた一覧は無視する。もう存在しないページや解放されたページは飛ばす
+        let saved = WarmList::load(&path).unwrap();
+        let list = WarmList {
+            pages: vec![
+                (1000, 5),
+                (page_ids[1].to_u64(), 2),
+                (page_ids[0].to_u64(), 1),
+            ],
+            ..saved
+        };
+        list.save(&path).unwrap();
+        let disk = DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        bufmgr.delete_page(page_ids[1]).unwrap();
+        assert_eq!(1, bufmgr.warm_up(&path).unwrap());
+        assert_eq!(1, bufmgr.stats().resident_pages);
+        fs::write(&path, b"garbage").unwrap();
+        assert_eq!(0, bufmgr.warm_up(&path).unwrap());
+        assert_eq!(0, bufmgr.warm_up(dir.path().join("missing")).unwrap());
+
+        // ページサイズが同じでも、別のデータベースの一覧は読み込まない
+        list.save(&path).unwrap();
+        let storage = FaultyStorage::new(FaultConfig::default());
+        let disk = DiskManager::with_storage(storage, DiskOptions::default()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        for _ in 0..8 {
+            bufmgr.create_page().unwrap();
+        }
+        bufmgr.flush().unwrap();
+        bufmgr.resize(0).unwrap();
+        bufmgr.resize(4).unwrap();
+        assert_eq!(0, bufmgr.warm_up(&path).unwrap());
+    }
+}
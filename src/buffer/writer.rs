@@ -0,0 +1,95 @@
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::buffer_pool_manager::BufferPoolManager;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BackgroundWriterOptions {
+    // 書き戻しを行う間隔
+    pub interval: Duration,
+    // 一回に書き戻すページの最大数
+    pub max_pages: usize,
+}
+
+impl Default for BackgroundWriterOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_millis(200),
+            max_pages: 100,
+        }
+    }
+}
+
+/*
+    変更されたページを一定の間隔で少しずつディスクに書き戻すスレッド。
+    追い出すページがあらかじめ書き戻されていれば、fetch_pageやcreate_pageが書き込みを待たずに済む。
+    書き戻すだけで同期はしないので、永続化にはこれまでどおりflushを呼ぶ必要がある。
+    BackgroundWriterを捨てるとスレッドが止まる。
+*/
+pub struct BackgroundWriter {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    pub fn start(bufmgr: Arc<BufferPoolManager>, options: BackgroundWriterOptions) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(options.interval) {
+                /*
+                    書き込みに失敗したページはis_dirtyが戻されるので、次の回か追い出すときにもう一度書かれる。
+                    エラーはそのときに呼び出し側へ返る。
+                */
+                let _ = bufmgr.write_dirty_pages(options.max_pages);
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        // 送信側を捨てるとrecv_timeoutがDisconnectedを返し、スレッドが終わる
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::{DiskManager, DiskOptions, MemoryStorage};
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    #[test]
+    fn test_background_writer() {
+        let disk = DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let bufmgr = Arc::new(BufferPoolManager::new(disk, BufferPool::new(4)));
+        let options = BackgroundWriterOptions {
+            interval: Duration::from_millis(1),
+            max_pages: 1,
+        };
+        let writer = BackgroundWriter::start(Arc::clone(&bufmgr), options);
+        let buffers: Vec<_> = (0..3).map(|_| bufmgr.create_page().unwrap()).collect();
+        // 少しずつ書き戻され、やがてすべてのページがis_dirtyでなくなる
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while buffers
+            .iter()
+            .any(|buffer| buffer.is_dirty.load(Ordering::SeqCst))
+        {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(1));
+        }
+        drop(writer);
+        assert_eq!(1, Arc::strong_count(&bufmgr));
+    }
+}
@@ -0,0 +1,79 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+use super::page::PageId;
+
+/*
+    DiskManagerがページを書き込む前に暗号化し、読み込んだ後に復号するためのトレイト。
+    暗号文には平文よりoverhead()バイトだけ大きく、ナンスと認証タグを含める。
+    ページIDを認証の対象に含めることで、別のページの位置に書き写された暗号文も検出できる。
+*/
+pub trait PageCipher: Send + Sync {
+    // 暗号化によって増える大きさ
+    fn overhead(&self) -> usize;
+    // plaintextを暗号化する。返す暗号文の大きさはplaintext.len() + overhead()
+    fn encrypt(&self, page_id: PageId, plaintext: &[u8]) -> Vec<u8>;
+    // ciphertextを復号する。鍵が違う場合や暗号文が書き換えられている場合はNoneを返す
+    fn decrypt(&self, page_id: PageId, ciphertext: &[u8]) -> Option<Vec<u8>>;
+}
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/*
+    ChaCha20-Poly1305でページを暗号化する。
+    ナンスは書き込みのたびに乱数で作り、暗号文の先頭に置く。
+*/
+pub struct ChaCha20Poly1305Cipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl ChaCha20Poly1305Cipher {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+}
+
+impl PageCipher for ChaCha20Poly1305Cipher {
+    fn overhead(&self) -> usize {
+        NONCE_SIZE + TAG_SIZE
+    }
+
+    fn encrypt(&self, page_id: PageId, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = page_id.to_u64().to_le_bytes();
+        let ciphertext = self
+            .aead
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .expect("page is too large to encrypt");
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    fn decrypt(&self, page_id: PageId, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        if ciphertext.len() < NONCE_SIZE + TAG_SIZE {
+            return None;
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_SIZE);
+        let aad = page_id.to_u64().to_le_bytes();
+        self.aead
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .ok()
+    }
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+
+use super::page::PageId;
+
+/*
+    コンパクションで移動したページの、古いページIDから新しいページIDへの対応。
+    ページの中に書かれているPageIdを書き換えるために、ページの形式を知っている上位の層に渡される。
+*/
+#[derive(Debug, Default)]
+pub struct Relocation {
+    moved: HashMap<PageId, PageId>,
+}
+
+impl Relocation {
+    pub(crate) fn insert(&mut self, from: PageId, to: PageId) {
+        self.moved.insert(from, to);
+    }
+
+    // page_idの移動先を返す。移動していないページや無効なページIDはそのまま返す
+    pub fn get(&self, page_id: PageId) -> PageId {
+        self.moved.get(&page_id).copied().unwrap_or(page_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moved.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.moved.len()
+    }
+}
+
+// コンパクションの結果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    // 使われているページの数
+    pub live_pages: u64,
+    // 前に詰めるために移動したページの数
+    pub moved_pages: u64,
+    // ファイルから取り除いた空きページの数
+    pub freed_pages: u64,
+    // 縮んだファイルの大きさ
+    pub reclaimed_bytes: u64,
+}
@@ -0,0 +1,376 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io;
+
+use super::cipher::PageCipher;
+use super::disk::Error;
+use super::page::PageId;
+use super::storage::Storage;
+
+// 圧縮したページを置く単位。ページの大きさはこの倍数に切り上げられる
+pub const SECTOR_SIZE: u64 = 512;
+// エクステントの先頭に置く、長さとチェックサムの大きさ
+const EXTENT_HEADER_SIZE: usize = 8;
+// 長さの最上位ビットが立っていれば、圧縮せずにそのまま保存されている
+const RAW_FLAG: u32 = 1 << 31;
+
+// ファイル上の連続した領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+}
+
+// 圧縮によってどれだけ領域が節約できているか
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    // 書き込まれたことのあるページの数
+    pub pages: u64,
+    // 圧縮する前のページの大きさの合計
+    pub logical_bytes: u64,
+    // 圧縮したページがファイル上で占めている大きさの合計
+    pub physical_bytes: u64,
+}
+
+impl CompressionStats {
+    pub fn saved_bytes(&self) -> u64 {
+        self.logical_bytes.saturating_sub(self.physical_bytes)
+    }
+    // 圧縮後の大きさの、圧縮前の大きさに対する割合
+    pub fn ratio(&self) -> f64 {
+        if self.logical_bytes == 0 {
+            return 1.0;
+        }
+        self.physical_bytes as f64 / self.logical_bytes as f64
+    }
+}
+
+/*
+    論理的なページIDから、圧縮したページが置かれている位置への対応表。
+    圧縮したページはセクター単位の可変長の領域(エクステント)に置かれ、
+    エクステントの先頭には保存した長さとチェックサムが書かれる。
+    対応表そのものもエクステントとして保存され、その位置はヘッダーに記録される。
+
+    最後に保存された対応表から参照されているエクステントは、次に対応表を保存するまで再利用しない。
+    こうしておくことで、保存の途中でクラッシュしても、ヘッダーが指す対応表とページは壊れずに残る。
+*/
+pub struct PageMap {
+    pages: Vec<Option<Extent>>,
+    // 対応表を保存しているエクステント
+    map_extent: Option<Extent>,
+    // 空いている領域。オフセットから長さへの対応
+    free: BTreeMap<u64, u64>,
+    // 対応表を保存するまで空き領域に戻せないエクステント
+    pending_free: Vec<Extent>,
+    // 使われている領域の末尾
+    end: u64,
+}
+
+impl PageMap {
+    // data_startより前はヘッダーのために空けておく
+    pub fn new(data_start: u64) -> Self {
+        Self {
+            pages: vec![None],
+            map_extent: None,
+            free: BTreeMap::new(),
+            pending_free: vec![],
+            end: align(data_start),
+        }
+    }
+
+    /*
+        map_offsetに保存されている対応表を読み込み、空き領域を組み立て直す。
+        max_payloadは一つのページを保存するときの中身の最大の大きさで、これより大きいエクステントは壊れているとみなす。
+    */
+    pub fn load(
+        storage: &dyn Storage,
+        data_start: u64,
+        map_offset: u64,
+        max_payload: usize,
+    ) -> io::Result<Self> {
+        let mut map = Self::new(data_start);
+        let (data, len) = read_extent(storage, map_offset, PageId::INVALID_PAGE_ID)?;
+        if data.len() % 16 != 0 {
+            return Err(invalid_data("page map is broken"));
+        }
+        let max_len = align((EXTENT_HEADER_SIZE + max_payload) as u64);
+        map.pages = data
+            .chunks_exact(16)
+            .map(|entry| {
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap());
+                let len = u64::from_le_bytes(entry[8..].try_into().unwrap());
+                if len > max_len {
+                    return Err(invalid_data("page map has a too large extent"));
+                }
+                Ok((len > 0).then_some(Extent { offset, len }))
+            })
+            .collect::<io::Result<_>>()?;
+        map.map_extent = Some(Extent {
+            offset: map_offset,
+            len,
+        });
+
+        let mut used: Vec<Extent> = map.pages.iter().flatten().copied().collect();
+        used.extend(map.map_extent);
+        used.sort_by_key(|extent| extent.offset);
+        let mut position = map.end;
+        for extent in used {
+            if extent.offset < position {
+                return Err(invalid_data("page map has overlapping extents"));
+            }
+            if extent.offset > position {
+                map.free.insert(position, extent.offset - position);
+            }
+            position = extent.end();
+        }
+        map.end = position;
+        Ok(map)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.pages.len() as u64
+    }
+
+    pub fn push(&mut self) -> PageId {
+        self.pages.push(None);
+        PageId(self.pages.len() as u64 - 1)
+    }
+
+    pub fn stats(&self, page_size: usize) -> CompressionStats {
+        let mut stats = CompressionStats::default();
+        for extent in self.pages.iter().flatten() {
+            stats.pages += 1;
+            stats.logical_bytes += page_size as u64;
+            stats.physical_bytes += extent.len;
+        }
+        stats
+    }
+
+    /*
+        ページを読み込む。一度も書き込まれていないページは0で埋める。
+        dataの長さはページサイズと同じでなければならない。
+    */
+    pub fn read_page(
+        &self,
+        storage: &dyn Storage,
+        cipher: Option<&dyn PageCipher>,
+        page_id: PageId,
+        data: &mut [u8],
+    ) -> Result<(), Error> {
+        let Some(extent) = self.pages[page_id.to_u64() as usize] else {
+            data.fill(0);
+            return Ok(());
+        };
+        let mut buf = vec![0u8; extent.len as usize];
+        storage.read_at(extent.offset, &mut buf)?;
+        let (stored, expected, payload) = decode_extent(&buf)?;
+        let actual = checksum(page_id, payload);
+        if expected != actual {
+            return Err(Error::Corrupted {
+                page_id,
+                expected,
+                actual,
+            });
+        }
+        // 暗号化している場合は、圧縮したものが暗号化されている
+        let payload = match cipher {
+            Some(cipher) => Cow::Owned(
+                cipher
+                    .decrypt(page_id, payload)
+                    .ok_or(Error::Unauthenticated(page_id))?,
+            ),
+            None => Cow::Borrowed(payload),
+        };
+        if stored & RAW_FLAG != 0 {
+            if payload.len() != data.len() {
+                return Err(invalid_data("stored page has a wrong size").into());
+            }
+            data.copy_from_slice(&payload);
+        } else {
+            let len = lz4_flex::decompress_into(&payload, data)
+                .map_err(|e| invalid_data(&e.to_string()))?;
+            if len != data.len() {
+                return Err(invalid_data("decompressed page has a wrong size").into());
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        pagesの(ページID, ページデータ)を圧縮し、まとめて書き込む。暗号化する場合は圧縮してから暗号化する。
+        最後に保存した対応表が今のエクステントを指しているかもしれないので、大きさが同じでも常に新しい領域に書き込む。
+        書き込みに失敗した場合は、対応表は変えずに新しく確保した領域を空き領域に戻す。
+    */
+    pub fn write_pages(
+        &mut self,
+        storage: &mut dyn Storage,
+        cipher: Option<&dyn PageCipher>,
+        pages: &[(PageId, &[u8])],
+    ) -> io::Result<()> {
+        let mut placed = Vec::with_capacity(pages.len());
+        let mut bufs = Vec::with_capacity(pages.len());
+        for &(page_id, data) in pages {
+            let compressed = lz4_flex::compress(data);
+            let (payload, raw) = if compressed.len() < data.len() {
+                (compressed.as_slice(), false)
+            } else {
+                (data, true)
+            };
+            let buf = match cipher {
+                Some(cipher) => encode_extent(page_id, &cipher.encrypt(page_id, payload), raw),
+                None => encode_extent(page_id, payload, raw),
+            };
+            let extent = self.allocate(align(buf.len() as u64));
+            placed.push((page_id.to_u64() as usize, extent));
+            bufs.push(buf);
+        }
+        let writes: Vec<(u64, &[u8])> = placed
+            .iter()
+            .zip(&bufs)
+            .map(|((_, extent), buf)| (extent.offset, buf.as_slice()))
+            .collect();
+        if let Err(e) = storage.write_batch(&writes) {
+            for &(_, extent) in &placed {
+                self.release(extent);
+            }
+            return Err(e);
+        }
+        for (index, extent) in placed {
+            // 古い領域は、対応表を保存するまで参照されている可能性がある
+            self.pending_free.extend(self.pages[index].replace(extent));
+        }
+        Ok(())
+    }
+
+    /*
+        対応表を新しいエクステントに書き込み、その位置を返す。
+        返した位置をヘッダーに記録して永続化したら、release_pendingを呼んで古い領域を解放する。
+    */
+    pub fn save(&mut self, storage: &mut dyn Storage) -> io::Result<u64> {
+        let mut data = Vec::with_capacity(self.pages.len() * 16);
+        for extent in &self.pages {
+            let extent = extent.unwrap_or(Extent { offset: 0, len: 0 });
+            data.extend_from_slice(&extent.offset.to_le_bytes());
+            data.extend_from_slice(&extent.len.to_le_bytes());
+        }
+        let buf = encode_extent(PageId::INVALID_PAGE_ID, &data, true);
+        let extent = self.allocate(align(buf.len() as u64));
+        storage.write_at(extent.offset, &buf)?;
+        self.pending_free.extend(self.map_extent.replace(extent));
+        Ok(extent.offset)
+    }
+
+    pub fn release_pending(&mut self) {
+        for extent in std::mem::take(&mut self.pending_free) {
+            self.release(extent);
+        }
+    }
+
+    // lenバイトの空き領域を探す。見つからなければ末尾を広げる
+    fn allocate(&mut self, len: u64) -> Extent {
+        let found = self
+            .free
+            .iter()
+            .find(|(_, &free_len)| free_len >= len)
+            .map(|(&offset, &free_len)| (offset, free_len));
+        if let Some((offset, free_len)) = found {
+            self.free.remove(&offset);
+            if free_len > len {
+                self.free.insert(offset + len, free_len - len);
+            }
+            return Extent { offset, len };
+        }
+        let extent = Extent {
+            offset: self.end,
+            len,
+        };
+        self.end += len;
+        extent
+    }
+
+    // 領域を空き領域に戻す。隣り合う空き領域とは一つにまとめる
+    fn release(&mut self, extent: Extent) {
+        let mut offset = extent.offset;
+        let mut len = extent.len;
+        if let Some((&prev, &prev_len)) = self.free.range(..offset).next_back() {
+            if prev + prev_len == offset {
+                self.free.remove(&prev);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(offset + len)) {
+            len += next_len;
+        }
+        if offset + len == self.end {
+            self.end = offset;
+        } else {
+            self.free.insert(offset, len);
+        }
+    }
+}
+
+fn align(len: u64) -> u64 {
+    len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE
+}
+
+fn checksum(page_id: PageId, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&page_id.to_u64().to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn encode_extent(page_id: PageId, payload: &[u8], raw: bool) -> Vec<u8> {
+    let mut stored = payload.len() as u32;
+    if raw {
+        stored |= RAW_FLAG;
+    }
+    let mut buf = Vec::with_capacity(align((EXTENT_HEADER_SIZE + payload.len()) as u64) as usize);
+    buf.extend_from_slice(&stored.to_le_bytes());
+    buf.extend_from_slice(&checksum(page_id, payload).to_le_bytes());
+    buf.extend_from_slice(payload);
+    // 読み込むときにエクステント全体を読めるように、セクターの境目まで埋めておく
+    buf.resize(align(buf.len() as u64) as usize, 0);
+    buf
+}
+
+// エクステントの先頭に書かれた長さとチェックサムを取り出し、中身の範囲と一緒に返す
+fn decode_extent(buf: &[u8]) -> io::Result<(u32, u32, &[u8])> {
+    let stored = u32::from_le_bytes(buf[..4].try_into().unwrap());
+    let checksum = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    let len = (stored & !RAW_FLAG) as usize;
+    let payload = buf
+        .get(EXTENT_HEADER_SIZE..EXTENT_HEADER_SIZE + len)
+        .ok_or_else(|| invalid_data("extent length is broken"))?;
+    Ok((stored, checksum, payload))
+}
+
+// offsetにあるエクステントを読み込み、中身とエクステントの大きさを返す
+fn read_extent(storage: &dyn Storage, offset: u64, page_id: PageId) -> io::Result<(Vec<u8>, u64)> {
+    let mut head = [0u8; EXTENT_HEADER_SIZE];
+    storage.read_at(offset, &mut head)?;
+    let len = (u32::from_le_bytes(head[..4].try_into().unwrap()) & !RAW_FLAG) as usize;
+    // 壊れた長さのために大きな領域を確保しないように、ファイルに収まる長さかを先に確かめる
+    let available = storage.size()?.saturating_sub(offset);
+    if (EXTENT_HEADER_SIZE + len) as u64 > available {
+        return Err(invalid_data("extent length is broken"));
+    }
+    let mut buf = vec![0u8; EXTENT_HEADER_SIZE + len];
+    storage.read_at(offset, &mut buf)?;
+    let (_, expected, payload) = decode_extent(&buf)?;
+    if checksum(page_id, payload) != expected {
+        return Err(invalid_data("extent checksum mismatch"));
+    }
+    Ok((payload.to_vec(), align(buf.len() as u64)))
+}
@@ -1,99 +1,1658 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io;
+use std::mem::size_of;
 use std::path::Path;
+use std::sync::Arc;
 
-use super::page::{PageId, PAGE_SIZE};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use zerocopy::AsBytes;
+
+use super::cipher::PageCipher;
+use super::compact::{CompactionStats, Relocation};
+use super::compress::{CompressionStats, PageMap};
+use super::double_write::{journal_path, DoubleWriteStorage};
+use super::header::{
+    Header, FLAG_COMPRESSED, FLAG_ENCRYPTED, FORMAT_VERSION, HEADER_AREA_SIZE, HEADER_PAGE_ID,
+    KEY_CHECK_PLAINTEXT, MAGIC, SUPPORTED_FLAGS,
+};
+use super::mmap::MmapStorage;
+use super::page::{is_valid_page_size, PageId, PAGE_SIZE};
+use super::segment::SegmentedStorage;
+use super::storage::{FileAt, FileStorage, Storage};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(
+        "page {page_id:?} is corrupted: expected checksum {expected:#010x}, actual {actual:#010x}"
+    )]
+    Corrupted {
+        page_id: PageId,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("not an artsdb database file")]
+    NotDatabase,
+    #[error(
+        "unsupported database format version {0} (supported {})",
+        FORMAT_VERSION
+    )]
+    UnsupportedVersion(u32),
+    #[error("invalid page size: {0} bytes")]
+    InvalidPageSize(usize),
+    #[error("database is encrypted but no key was given")]
+    KeyRequired,
+    #[error("wrong encryption key")]
+    WrongKey,
+    #[error("page {0:?} failed authentication")]
+    Unauthenticated(PageId),
+    #[error("database is locked by another process")]
+    Locked,
+    #[error("database is opened read-only")]
+    ReadOnly,
+}
+
+// ページの末尾に置くチェックサムの大きさ
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Clone)]
+pub struct DiskOptions {
+    /*
+        ヘッダーページもチェックサムも持たない、古い形式のファイルとして開くかどうか。
+        古い形式では先頭のページにB-treeのメタページが置かれており、ページの解放はできない。
+    */
+    pub legacy: bool,
+    /*
+        新しくデータベースを作るときのページサイズ。作った後はヘッダーに記録された値が使われる。
+        ヘッダーのない古い形式のファイルでは、常にこの値が使われる。
+    */
+    pub page_size: usize,
+    /*
+        新しくデータベースを作るときに、ページを圧縮して保存するかどうか。
+        既存のデータベースを開くときは、ヘッダーに記録された設定が使われる。
+    */
+    pub compression: bool,
+    /*
+        ページの暗号化に使う鍵を持った暗号。
+        新しくデータベースを作るときに指定すると暗号化され、その後は同じ鍵でなければ開けなくなる。
+        ナンスと認証タグはページの中に置くので、読み書きできるページデータはその分だけ小さくなる(data_sizeを参照)。
+    */
+    pub cipher: Option<Arc<dyn PageCipher>>,
+    /*
+        ページを本来の位置に書き込む前に、ダブルライト領域に書いて永続化するかどうか。
+        パスを指定して開く場合に、データベースファイルの隣に置く".dwb"ファイルを使う。
+        with_storageで開く場合は、DoubleWriteStorageで包んだストレージを渡す。
+    */
+    pub double_write: bool,
+    /*
+        読み込み専用で開くかどうか。ファイルは作られず、ページの書き込みや確保はエラーになる。
+        他のプロセスも読み込み専用であれば、同じファイルを同時に開ける。
+        ダブルライトのジャーナルからの復元は書き込みを伴うので、読み込み専用では行わない。
+    */
+    pub read_only: bool,
+    /*
+        ファイルをメモリにマップし、ページの読み込みをマップから行うかどうか。
+        書き込みはこれまでどおりファイルに対して行う。一つのファイルを開く場合に使われ、
+        セグメントファイルに分けて開く場合は使えない。
+    */
+    pub mmap: bool,
+}
+
+impl Default for DiskOptions {
+    fn default() -> Self {
+        Self {
+            legacy: false,
+            page_size: PAGE_SIZE,
+            compression: false,
+            cipher: None,
+            double_write: false,
+            read_only: false,
+            mmap: false,
+        }
+    }
+}
+
+// 鍵を表示してしまわないように、暗号は指定されているかどうかだけを表示する
+impl fmt::Debug for DiskOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DiskOptions")
+            .field("legacy", &self.legacy)
+            .field("page_size", &self.page_size)
+            .field("compression", &self.compression)
+            .field("cipher", &self.cipher.is_some())
+            .field("double_write", &self.double_write)
+            .field("read_only", &self.read_only)
+            .field("mmap", &self.mmap)
+            .finish()
+    }
+}
+
+/*
+    解放されたページの先頭に書き込む目印。
+    目印の後ろには次の空きページのIDが書かれ、ヘッダーのfree_list_headから辿れる連結リストになる。
+*/
+const FREE_PAGE_MAGIC: [u8; 8] = *b"FREEPAGE";
 
 pub struct DiskManager {
-    heap_file: File,
+    storage: Box<dyn Storage>,
+    options: DiskOptions,
+    // 先頭ページに保存されているヘッダーの内容
+    header: Header,
+    // 1ページの大きさ
+    page_size: usize,
     next_page_id: u64,
+    // ページを圧縮している場合の、ページIDからファイル上の位置への対応表
+    page_map: Option<PageMap>,
+    /*
+        空きページのリストに繋がっているページ。開くときにリストを辿って集めておく。
+        ページの中身ではなくこちらで判断するので、目印と同じ内容で始まるページも解放できる。
+    */
+    free_pages: HashSet<PageId>,
 }
 
 impl DiskManager {
-    pub fn new(heap_file: File) -> io::Result<Self> {
-        // ファイルサイズを取得
-        let heap_file_size = heap_file.metadata()?.len();
-        let next_page_id = heap_file_size / PAGE_SIZE as u64;
-        Ok(Self {
-            heap_file,
-            next_page_id,
-        })
+    pub fn new(heap_file: File) -> Result<Self, Error> {
+        Self::new_with(heap_file, DiskOptions::default())
     }
-    pub fn open(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
-        let heap_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(heap_file_path)?;
-        Self::new(heap_file)
+    pub fn new_with(heap_file: File, options: DiskOptions) -> Result<Self, Error> {
+        if options.double_write {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "double write needs the database path to place its journal",
+            )
+            .into());
+        }
+        lock_file(&heap_file, options.read_only)?;
+        if options.mmap {
+            return Self::with_storage(MmapStorage::new(heap_file)?, options);
+        }
+        Self::with_storage(FileStorage::new(heap_file), options)
+    }
+    // ファイル以外のストレージを使ってDiskManagerを作る
+    pub fn with_storage(
+        storage: impl Storage + 'static,
+        options: DiskOptions,
+    ) -> Result<Self, Error> {
+        Self::with_segmented_storage(storage, options, 0)
+    }
+    /*
+        segment_pagesページずつのセグメントに分けたストレージを使って開く。0であれば分けていない。
+        ヘッダーに記録されたセグメントの大きさと違えば、ページの位置がずれるので開けない。
+    */
+    fn with_segmented_storage(
+        storage: impl Storage + 'static,
+        options: DiskOptions,
+        segment_pages: u64,
+    ) -> Result<Self, Error> {
+        if !is_valid_page_size(options.page_size) {
+            return Err(Error::InvalidPageSize(options.page_size));
+        }
+        if options.legacy && (options.compression || options.cipher.is_some()) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "legacy format does not support compression or encryption",
+            )
+            .into());
+        }
+        // ストレージの大きさを取得
+        let storage_size = storage.size()?;
+        let page_size = options.page_size;
+        let mut disk = Self {
+            storage: Box::new(storage),
+            options,
+            header: Header::new(page_size),
+            page_size,
+            next_page_id: 0,
+            page_map: None,
+            free_pages: HashSet::new(),
+        };
+        if disk.options.legacy {
+            if storage_size > 0 {
+                disk.header.catalog_root = PageId(0);
+            }
+        } else if storage_size == 0 {
+            if disk.options.read_only {
+                return Err(Error::NotDatabase);
+            }
+            // 新しいファイルにはヘッダーを書き込んでおく
+            if disk.options.compression {
+                disk.header.flags |= FLAG_COMPRESSED;
+            }
+            if let Some(cipher) = &disk.options.cipher {
+                // 開くときに鍵が正しいかを確かめられるように、決まった平文を暗号化して残しておく
+                let key_check = cipher.encrypt(PageId::INVALID_PAGE_ID, &KEY_CHECK_PLAINTEXT);
+                if key_check.len() > disk.header.key_check.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "cipher overhead is too large",
+                    )
+                    .into());
+                }
+                disk.header.key_check[..key_check.len()].copy_from_slice(&key_check);
+                disk.header.flags |= FLAG_ENCRYPTED;
+            }
+            disk.header.segment_pages = segment_pages;
+            disk.header.database_id = new_database_id();
+            disk.write_header()?;
+        } else {
+            disk.read_header()?;
+            disk.check_segment_pages(segment_pages)?;
+            if disk.header.compact_staging != 0 {
+                disk.finish_compaction()?;
+            }
+        }
+        if disk.header.flags & FLAG_COMPRESSED != 0 {
+            // 圧縮したページはヘッダーの後ろに詰めて置かれる
+            let data_start = disk.page_size as u64;
+            let page_map = match disk.header.page_map_offset {
+                0 => PageMap::new(data_start),
+                offset => PageMap::load(
+                    disk.storage.as_ref(),
+                    data_start,
+                    offset,
+                    disk.page_size + cipher_overhead(&disk.options),
+                )?,
+            };
+            disk.next_page_id = page_map.len();
+            disk.page_map = Some(page_map);
+        } else {
+            let storage_size = disk.storage.size()?;
+            disk.next_page_id = storage_size / disk.page_size as u64;
+        }
+        disk.load_free_pages()?;
+        Ok(disk)
+    }
+    pub fn open(heap_file_path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with(heap_file_path, DiskOptions::default())
+    }
+    pub fn open_with(
+        heap_file_path: impl AsRef<Path>,
+        options: DiskOptions,
+    ) -> Result<Self, Error> {
+        let heap_file = open_file(heap_file_path.as_ref(), options.read_only)?;
+        lock_file(&heap_file, options.read_only)?;
+        if options.mmap {
+            let storage = MmapStorage::new(heap_file)?;
+            return Self::with_path_storage(storage, heap_file_path.as_ref(), options, 0);
+        }
+        Self::with_path_storage(
+            FileStorage::new(heap_file),
+            heap_file_path.as_ref(),
+            options,
+            0,
+        )
+    }
+    // pathに置かれたデータベースのストレージを使って開く。ダブルライトのジャーナルはpathの隣に置く
+    fn with_path_storage(
+        storage: impl Storage + 'static,
+        path: &Path,
+        options: DiskOptions,
+        segment_pages: u64,
+    ) -> Result<Self, Error> {
+        if options.double_write && !options.read_only {
+            let journal = FileStorage::new(open_file(&journal_path(path), false)?);
+            let storage = DoubleWriteStorage::new(storage, journal)?;
+            return Self::with_segmented_storage(storage, options, segment_pages);
+        }
+        Self::with_segmented_storage(storage, options, segment_pages)
     }
-    // 新しいPageIdを採番する
-    pub fn allocate_page(&mut self) -> PageId {
-        let page_id = self.next_page_id;
+    // ファイル上のページの大きさ
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+    /*
+        read_page_dataやwrite_page_dataで読み書きするページデータの大きさ。これより大きいデータは書き込めない。
+        ページの末尾にチェックサムの4バイトと暗号化の分(PageCipher::overhead)を置くので、その分だけページサイズより小さくなる。
+        legacyのファイルと圧縮したデータベースでは、ページサイズと同じになる。
+    */
+    pub fn data_size(&self) -> usize {
+        if self.page_map.is_some() {
+            // 圧縮したページは、エクステントの先頭にチェックサムを置く
+            return self.page_size;
+        }
+        data_size(self.page_size, &self.options)
+    }
+    /*
+        ページをセグメントファイルに分けて保存するデータベースを開きます。
+        各セグメントにはsegment_pagesページずつ保存され、ページがセグメントの境目をまたぐことはありません。
+    */
+    pub fn open_segmented(
+        base_path: impl AsRef<Path>,
+        segment_pages: u64,
+        options: DiskOptions,
+    ) -> Result<Self, Error> {
+        if options.mmap {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "mmap is not supported for segmented storage",
+            )
+            .into());
+        }
+        let mut options = options;
+        let locked = |e: io::Error| match e.kind() {
+            io::ErrorKind::WouldBlock => Error::Locked,
+            _ => e.into(),
+        };
+        // ヘッダーを読む前に先頭のセグメントをロックし、読んでいる間に書き換えられないようにする
+        let first =
+            SegmentedStorage::open_first(base_path.as_ref(), options.read_only).map_err(locked)?;
+        // 既存のデータベースであれば、ヘッダーに記録されたページサイズを使ってセグメントの大きさを決める
+        if !options.legacy {
+            let mut area = [0u8; HEADER_AREA_SIZE];
+            let read = first.read_exact_at(&mut area, 0);
+            let header = Header::decode(&area);
+            if read.is_ok() && header.magic == MAGIC {
+                if header.flags & FLAG_ENCRYPTED != 0 && options.cipher.is_none() {
+                    return Err(Error::KeyRequired);
+                }
+                options.page_size = header.page_size as usize;
+            }
+        }
+        let segment_size = segment_pages * options.page_size as u64;
+        let storage =
+            SegmentedStorage::with_first(&base_path, first, segment_size, options.read_only)?;
+        Self::with_path_storage(storage, base_path.as_ref(), options, segment_pages)
+    }
+    fn page_offset(&self, page_id: PageId) -> u64 {
+        self.page_size as u64 * page_id.to_u64()
+    }
+    // ヘッダーを読み込み、このデータベースのファイルとして扱えるかを確かめる
+    fn read_header(&mut self) -> Result<(), Error> {
+        let mut area = [0u8; HEADER_AREA_SIZE];
+        match self.storage.read_at(0, &mut area) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(Error::NotDatabase),
+            result => result?,
+        }
+        let header = Header::decode(&area);
+        if header.magic != MAGIC {
+            return Err(Error::NotDatabase);
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        let actual = Header::compute_checksum(&area);
+        if header.checksum != actual {
+            return Err(Error::Corrupted {
+                page_id: HEADER_PAGE_ID,
+                expected: header.checksum,
+                actual,
+            });
+        }
+        // 知らない機能を使っているファイルは読み書きできない
+        if header.flags & !SUPPORTED_FLAGS != 0 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "header has unknown flags").into(),
+            );
+        }
+        if !is_valid_page_size(header.page_size as usize) {
+            return Err(Error::InvalidPageSize(header.page_size as usize));
+        }
+        match (header.flags & FLAG_ENCRYPTED != 0, &self.options.cipher) {
+            (true, None) => return Err(Error::KeyRequired),
+            (true, Some(cipher)) => {
+                let len = KEY_CHECK_PLAINTEXT.len() + cipher.overhead();
+                let key_check = header.key_check.get(..len).ok_or(Error::WrongKey)?;
+                if cipher
+                    .decrypt(PageId::INVALID_PAGE_ID, key_check)
+                    .as_deref()
+                    != Some(&KEY_CHECK_PLAINTEXT[..])
+                {
+                    return Err(Error::WrongKey);
+                }
+            }
+            (false, Some(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "database is not encrypted",
+                )
+                .into())
+            }
+            (false, None) => {}
+        }
+        self.page_size = header.page_size as usize;
+        self.header = header;
+        Ok(())
+    }
+    // ヘッダーに記録されたセグメントの大きさが、開くときに指定したものと同じかを確かめる
+    fn check_segment_pages(&self, segment_pages: u64) -> Result<(), Error> {
+        match self.header.segment_pages {
+            recorded if recorded == segment_pages => Ok(()),
+            0 => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "database is not split into segments",
+            )
+            .into()),
+            recorded => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("database is split into segments of {} pages", recorded),
+            )
+            .into()),
+        }
+    }
+    // ヘッダーを書き込む
+    fn write_header(&mut self) -> Result<(), Error> {
+        let area = self.header.encode();
+        self.header.checksum = Header::compute_checksum(&area);
+        let mut slot = vec![0u8; self.page_size];
+        slot[..area.len()].copy_from_slice(&area);
+        self.storage.write_at(0, &slot)?;
+        Ok(())
+    }
+    /*
+        変更したヘッダーを保存する。
+        圧縮している場合は、ヘッダーが対応表に載っていないページを指さないように、
+        syncで対応表と一緒に書き込む。
+    */
+    fn update_header(&mut self) -> Result<(), Error> {
+        if self.page_map.is_some() {
+            return Ok(());
+        }
+        self.write_header()
+    }
+    // ページを圧縮している場合に、圧縮によって節約できている領域の大きさを返す
+    pub fn compression_stats(&self) -> Option<CompressionStats> {
+        self.page_map
+            .as_ref()
+            .map(|page_map| page_map.stats(self.page_size))
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+    // ページIDが読み書きできるページを指しているかどうか
+    pub fn is_valid_page_id(&self, page_id: PageId) -> bool {
+        page_id.to_u64() < self.next_page_id && (self.options.legacy || page_id != HEADER_PAGE_ID)
+    }
+    /*
+        データベースを作ったときに決めた識別子を返す。
+        ヘッダーを持たないlegacyのファイルでは0になる
+    */
+    pub fn database_id(&self) -> u64 {
+        self.header.database_id
+    }
+    // カタログ(最初に辿るB-treeのメタページ)のページIDを返す
+    pub fn catalog_root(&self) -> Option<PageId> {
+        self.header.catalog_root.valid()
+    }
+    pub fn set_catalog_root(&mut self, page_id: PageId) -> Result<(), Error> {
+        self.check_writable()?;
+        if self.options.legacy {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "legacy format has no header to store the catalog root",
+            )
+            .into());
+        }
+        self.header.catalog_root = page_id;
+        self.update_header()
+    }
+    // 空きページのリストを先頭から辿り、空きページを集める
+    fn load_free_pages(&mut self) -> Result<(), Error> {
+        let mut data = vec![0u8; self.data_size()];
+        let mut next = self.header.free_list_head;
+        while let Some(page_id) = next.valid() {
+            if !self.is_valid_page_id(page_id) || !self.free_pages.insert(page_id) {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "free page list is broken").into(),
+                );
+            }
+            // 先頭のページの次はヘッダーに記録されていればそちらを使う。先頭のページには目印が書かれていないことがある
+            next = match self.header.free_list_next {
+                next if page_id == self.header.free_list_head && next != HEADER_PAGE_ID => next,
+                _ => self.read_free_page(page_id, &mut data)?,
+            };
+        }
+        Ok(())
+    }
+    // 空きページを読み込み、そこに書かれている次の空きページのIDを返す
+    fn read_free_page(&self, page_id: PageId, data: &mut [u8]) -> Result<PageId, Error> {
+        self.read_page_data(page_id, data)?;
+        if !data.starts_with(&FREE_PAGE_MAGIC) {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "free page list is broken").into(),
+            );
+        }
+        Ok(PageId::from(
+            &data[FREE_PAGE_MAGIC.len()..FREE_PAGE_MAGIC.len() + size_of::<PageId>()],
+        ))
+    }
+    // 新しいPageIdを採番する。解放済みのページがあればそちらを先に再利用する
+    pub fn allocate_page(&mut self) -> Result<PageId, Error> {
+        self.check_writable()?;
+        if let Some(page_id) = self.header.free_list_head.valid() {
+            // 空きページには目印と次の空きページのIDが書かれている
+            let mut data = vec![0u8; self.data_size()];
+            self.header.free_list_head = match self.header.free_list_next {
+                HEADER_PAGE_ID => self.read_free_page(page_id, &mut data)?,
+                next => next,
+            };
+            // 新しい先頭のページの目印はすでに書き込まれているので、次のページは記録しなくてよい
+            self.header.free_list_next = HEADER_PAGE_ID;
+            self.update_header()?;
+            self.free_pages.remove(&page_id);
+            // 新しく確保したページと同じように、0で埋めておく
+            data.fill(0);
+            self.write_page_data(page_id, &data)?;
+            return Ok(page_id);
+        }
+        if let Some(page_map) = &mut self.page_map {
+            // 圧縮している場合は、書き込まれるまでファイル上の領域を使わない
+            let page_id = page_map.push();
+            self.next_page_id += 1;
+            return Ok(page_id);
+        }
+        let page_id = PageId(self.next_page_id);
+        // ページの領域を確保しておく。確保したばかりのページはすべて0で埋められている
+        self.storage
+            .allocate(self.page_offset(page_id) + self.page_size as u64)?;
         self.next_page_id += 1;
-        PageId(page_id)
+        Ok(page_id)
     }
+    // ページを解放し、次のallocate_pageで再利用できるようにする
+    pub fn deallocate_page(&mut self, page_id: PageId) -> Result<(), Error> {
+        self.check_writable()?;
+        if self.options.legacy {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "legacy format does not support deallocation",
+            )
+            .into());
+        }
+        if !self.is_valid_page_id(page_id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID").into());
+        }
+        if self.is_free_page(page_id) {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "Page is already deallocated").into(),
+            );
+        }
+        // 空きページのリストの先頭に繋ぐ
+        let mut data = vec![0u8; self.data_size()];
+        data[..FREE_PAGE_MAGIC.len()].copy_from_slice(&FREE_PAGE_MAGIC);
+        data[FREE_PAGE_MAGIC.len()..FREE_PAGE_MAGIC.len() + size_of::<PageId>()]
+            .copy_from_slice(self.header.free_list_head.as_bytes());
+        /*
+            先にヘッダーを更新し、次のページもヘッダーに記録しておく。
+            目印を書き込む前にクラッシュしても、ページはリストに繋がったまま失われない。
+        */
+        self.header.free_list_next = self.header.free_list_head;
+        self.header.free_list_head = page_id;
+        self.update_header()?;
+        self.free_pages.insert(page_id);
+        self.write_page_data(page_id, &data)
+    }
+    // ページが解放されて空きページのリストに繋がっているかどうか
+    pub fn is_free_page(&self, page_id: PageId) -> bool {
+        self.free_pages.contains(&page_id)
+    }
+
     // 指定されたページIDのページデータを読み込み、バイト配列に書き込みます。
-    pub fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+    pub fn read_page_data(&self, page_id: PageId, data: &mut [u8]) -> Result<(), Error> {
         //　pageIDが不正な場合はエラーを返す
-        if page_id.to_u64() >= self.next_page_id {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid page ID",
-            ));
+        if !self.is_valid_page_id(page_id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID").into());
         }
 
-        // データサイズがPAGE_SIZEと一致しない場合はエラーを返す
-        if data.len() != PAGE_SIZE {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid data buffer size",
-            ));
+        // データサイズがページサイズと一致しない場合はエラーを返す
+        if data.len() != self.data_size() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "Invalid data buffer size").into(),
+            );
         }
 
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
-        self.heap_file.read_exact(data)
+        if let Some(page_map) = &self.page_map {
+            return page_map.read_page(
+                self.storage.as_ref(),
+                self.options.cipher.as_deref(),
+                page_id,
+                data,
+            );
+        }
+
+        let offset = self.page_offset(page_id);
+        if self.options.legacy {
+            self.storage.read_at(offset, data)?;
+            return Ok(());
+        }
+
+        let mut slot = vec![0u8; self.page_size];
+        self.storage.read_at(offset, &mut slot)?;
+        self.decode_slot(page_id, &slot, data)
+    }
+    /*
+        first_page_idから始まる連続したページのページデータを、pagesに順に読み込みます。
+        圧縮していない場合は、一回の読み込みにまとめられます。
+    */
+    pub fn read_pages_data(
+        &self,
+        first_page_id: PageId,
+        pages: &mut [&mut [u8]],
+    ) -> Result<(), Error> {
+        if self.page_map.is_some() || pages.len() <= 1 {
+            for (i, data) in pages.iter_mut().enumerate() {
+                self.read_page_data(PageId(first_page_id.to_u64() + i as u64), data)?;
+            }
+            return Ok(());
+        }
+        for (i, data) in pages.iter().enumerate() {
+            if !self.is_valid_page_id(PageId(first_page_id.to_u64() + i as u64)) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID").into());
+            }
+            if data.len() != self.data_size() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid data buffer size",
+                )
+                .into());
+            }
+        }
+        let mut slots = vec![0u8; self.page_size * pages.len()];
+        self.storage
+            .read_at(self.page_offset(first_page_id), &mut slots)?;
+        for (i, (slot, data)) in slots
+            .chunks(self.page_size)
+            .zip(pages.iter_mut())
+            .enumerate()
+        {
+            let page_id = PageId(first_page_id.to_u64() + i as u64);
+            if self.options.legacy {
+                data.copy_from_slice(slot);
+            } else {
+                self.decode_slot(page_id, slot, data)?;
+            }
+        }
+        Ok(())
+    }
+    // ページとチェックサムを並べた領域を検証し、ページデータを取り出す
+    fn decode_slot(&self, page_id: PageId, slot: &[u8], data: &mut [u8]) -> Result<(), Error> {
+        // ページの後ろに保存されているチェックサムと、読み込んだデータから計算した値を比べる
+        let (page, stored) = slot.split_at(self.page_size - CHECKSUM_SIZE);
+        let expected = u32::from_le_bytes(stored.try_into().unwrap());
+        // 一度も書き込まれていないページはすべて0になっているので、壊れているとはみなさない
+        if expected == 0 && page.iter().all(|&b| b == 0) {
+            data.fill(0);
+            return Ok(());
+        }
+        let actual = checksum(page_id, page);
+        if expected != actual {
+            return Err(Error::Corrupted {
+                page_id,
+                expected,
+                actual,
+            });
+        }
+        match &self.options.cipher {
+            Some(cipher) => {
+                let plaintext = cipher
+                    .decrypt(page_id, page)
+                    .ok_or(Error::Unauthenticated(page_id))?;
+                data.copy_from_slice(&plaintext);
+            }
+            None => data.copy_from_slice(page),
+        }
+        Ok(())
     }
     // 指定されたページIDの位置にページデータを書き込みます。
-    pub fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
-        // offsetを計算
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
-        self.heap_file.write_all(data)
+    pub fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> Result<(), Error> {
+        self.write_pages_data(page_id, &[data])
+    }
+    /*
+        first_page_idから始まる連続したページに、pagesのページデータを順に書き込みます。
+        隣り合うページは一回の書き込みにまとめられます。
+    */
+    pub fn write_pages_data(
+        &mut self,
+        first_page_id: PageId,
+        pages: &[&[u8]],
+    ) -> Result<(), Error> {
+        self.write_page_runs(&[(first_page_id, pages)])
+    }
+    /*
+        runsの(先頭のページID, ページデータ)ごとに、連続したページとして書き込みます。
+        すべてのページをストレージへの一回の書き込みとして渡すので、
+        ダブルライトを使う場合もジャーナルの永続化は一度で済みます。
+    */
+    pub fn write_page_runs(&mut self, runs: &[(PageId, &[&[u8]])]) -> Result<(), Error> {
+        self.check_writable()?;
+        let pages: Vec<(PageId, &[u8])> = runs
+            .iter()
+            .flat_map(|&(first_page_id, pages)| {
+                pages
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, &data)| (PageId(first_page_id.to_u64() + i as u64), data))
+            })
+            .collect();
+        for &(page_id, data) in &pages {
+            if !self.options.legacy && page_id == HEADER_PAGE_ID {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid page ID").into());
+            }
+            if data.len() != self.data_size() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid data buffer size",
+                )
+                .into());
+            }
+        }
+        // 圧縮している場合は、ページごとに大きさが変わるので対応表に置き場所を決めてもらう
+        if let Some(page_map) = &mut self.page_map {
+            page_map.write_pages(
+                self.storage.as_mut(),
+                self.options.cipher.as_deref(),
+                &pages,
+            )?;
+            return Ok(());
+        }
+        // 連続したページごとに、各ページのスロットを一つのバッファに並べる
+        let mut bufs = Vec::with_capacity(runs.len());
+        for &(first_page_id, run) in runs {
+            let mut buf = Vec::with_capacity(run.len() * self.page_size);
+            for (i, data) in run.iter().enumerate() {
+                let page_id = PageId(first_page_id.to_u64() + i as u64);
+                self.encode_slot(page_id, data, &mut buf);
+            }
+            bufs.push((self.page_offset(first_page_id), buf));
+        }
+        let writes: Vec<(u64, &[u8])> = bufs
+            .iter()
+            .map(|(offset, buf)| (*offset, buf.as_slice()))
+            .collect();
+        self.storage.write_batch(&writes)?;
+        Ok(())
+    }
+
+    // page_idのページとしてファイルに置くスロットを、bufの後ろに書き足す
+    fn encode_slot(&self, page_id: PageId, data: &[u8], buf: &mut Vec<u8>) {
+        // 暗号化する場合は、暗号文をページデータの代わりに書き込む
+        let data = match &self.options.cipher {
+            Some(cipher) => Cow::Owned(cipher.encrypt(page_id, data)),
+            None => Cow::Borrowed(data),
+        };
+        buf.extend_from_slice(&data);
+        // チェックサムを使う場合は、ページデータの後ろにチェックサムを置く
+        if !self.options.legacy {
+            buf.extend_from_slice(&checksum(page_id, &data).to_le_bytes());
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<(), Error> {
+        // 読み込み専用であれば、永続化すべき変更はない
+        if self.options.read_only {
+            return Ok(());
+        }
+        self.storage.sync()?;
+        if let Some(page_map) = &mut self.page_map {
+            /*
+                対応表を書き込んで永続化してから、ヘッダーを新しい対応表に向ける。
+                ヘッダーが書き換わるまでは古い対応表とそこから参照されるページが残っているので、
+                途中でクラッシュしても最後にsyncした状態に戻るだけで済む。
+            */
+            self.header.page_map_offset = page_map.save(self.storage.as_mut())?;
+            self.storage.sync()?;
+            self.write_header()?;
+            self.storage.sync()?;
+            if let Some(page_map) = &mut self.page_map {
+                page_map.release_pending();
+            }
+        }
+        Ok(())
+    }
+    /*
+        使われているページをファイルの前の方に詰め、空いた後ろの部分を切り詰めます。
+        ヘッダーのカタログの位置と空きページのリストはこのメソッドが書き換えます。
+        ページの中に書かれたPageIdの形式はこの層では分からないので、
+        B-treeのノードやメタページが指しているPageIdは、rewriteが各ページの中身を移動先の対応に従って書き換えます。
+        他にこのデータベースを使っているものがない状態で実行してください。
+
+        詰めた後のページはまずファイルの後ろに書き写して永続化し、ヘッダーを書き換えて確定してから本来の位置に写します。
+        確定する前にクラッシュすれば元のまま、確定した後であれば開き直したときに写し終えるので、ページの参照は壊れません。
+    */
+    pub fn compact(
+        &mut self,
+        mut rewrite: impl FnMut(&mut [u8], &Relocation),
+    ) -> Result<CompactionStats, Error> {
+        self.check_writable()?;
+        if self.options.legacy || self.page_map.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compaction is not supported for legacy or compressed databases",
+            )
+            .into());
+        }
+        let free = self.free_pages.clone();
+        let mut data = vec![0u8; self.data_size()];
+        let live: Vec<PageId> = (HEADER_PAGE_ID.to_u64() + 1..self.next_page_id)
+            .map(PageId)
+            .filter(|page_id| !free.contains(page_id))
+            .collect();
+        let next_page_id = HEADER_PAGE_ID.to_u64() + 1 + live.len() as u64;
+        let old_size = self.storage.size()?;
+
+        // 詰めた後の範囲からはみ出すページを、範囲の中の空きページに移す
+        let mut holes = (HEADER_PAGE_ID.to_u64() + 1..next_page_id)
+            .map(PageId)
+            .filter(|page_id| free.contains(page_id));
+        let mut relocation = Relocation::default();
+        for &page_id in live.iter().rev() {
+            if page_id.to_u64() < next_page_id {
+                break;
+            }
+            relocation.insert(page_id, holes.next().unwrap());
+        }
+        // 書き写す場所を先に記録しておき、書き写している途中でクラッシュしても開き直したときに取り除けるようにする
+        let staging = self.next_page_id;
+        self.header.compact_staging = staging;
+        self.write_header()?;
+        self.storage.sync()?;
+
+        // 詰めた後のページを移動先のページとして暗号化やチェックサムを済ませ、今のファイルの後ろに並べる
+        let page_size = self.page_size as u64;
+        self.storage
+            .allocate((staging + next_page_id - 1) * page_size)?;
+        let mut slot = Vec::with_capacity(self.page_size);
+        for &page_id in &live {
+            self.read_page_data(page_id, &mut data)?;
+            rewrite(&mut data, &relocation);
+            let to = relocation.get(page_id);
+            slot.clear();
+            self.encode_slot(to, &data, &mut slot);
+            self.storage
+                .write_at((staging + to.to_u64() - 1) * page_size, &slot)?;
+        }
+        self.storage.sync()?;
+
+        // 書き写したページが永続化されてから、ヘッダーを書き換えて確定する
+        self.header.catalog_root = relocation.get(self.header.catalog_root);
+        self.header.free_list_head = PageId::INVALID_PAGE_ID;
+        self.header.free_list_next = HEADER_PAGE_ID;
+        self.header.compact_end = next_page_id;
+        self.write_header()?;
+        self.storage.sync()?;
+        self.free_pages.clear();
+        self.finish_compaction()?;
+        let new_size = next_page_id * page_size;
+        Ok(CompactionStats {
+            live_pages: live.len() as u64,
+            moved_pages: relocation.len() as u64,
+            freed_pages: free.len() as u64,
+            reclaimed_bytes: old_size.saturating_sub(new_size),
+        })
+    }
+    /*
+        ヘッダーに記録されたコンパクションを終わらせる。
+        確定していなければ書き写していたページを捨て、確定していれば書き写したページを本来の位置に写してから切り詰める。
+    */
+    fn finish_compaction(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        let page_size = self.page_size as u64;
+        let staging = self.header.compact_staging;
+        let end = self.header.compact_end;
+        if end == 0 {
+            self.storage.truncate(staging * page_size)?;
+        } else {
+            /*
+                切り詰めた後であれば、写し終えている。
+                写している途中でクラッシュしても、書き写したページが残っているのでやり直せる。
+            */
+            if self.storage.size()? >= (staging + end - 1) * page_size {
+                let mut slot = vec![0u8; self.page_size];
+                for page_id in HEADER_PAGE_ID.to_u64() + 1..end {
+                    self.storage
+                        .read_at((staging + page_id - 1) * page_size, &mut slot)?;
+                    self.storage.write_at(page_id * page_size, &slot)?;
+                }
+                self.storage.sync()?;
+            }
+            self.storage.truncate(end * page_size)?;
+            self.next_page_id = end;
+        }
+        self.storage.sync()?;
+        self.header.compact_staging = 0;
+        self.header.compact_end = 0;
+        self.write_header()?;
+        self.storage.sync()?;
+        Ok(())
+    }
+}
+
+// 読み込み専用の場合はファイルを作らない
+fn open_file(path: &Path, read_only: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .create(!read_only)
+        .truncate(false)
+        .open(path)
+}
+
+// 書き込む場合は排他ロック、読み込み専用の場合は共有ロックをかける。ロックはファイルを閉じると外れる
+fn lock_file(file: &File, read_only: bool) -> Result<(), Error> {
+    let result = if read_only {
+        fs2::FileExt::try_lock_shared(file)
+    } else {
+        fs2::FileExt::try_lock_exclusive(file)
+    };
+    result.map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock => Error::Locked,
+        _ => e.into(),
+    })
+}
+
+// 1ページに読み書きできるページデータの大きさ
+fn data_size(page_size: usize, options: &DiskOptions) -> usize {
+    if options.legacy {
+        return page_size;
     }
+    page_size - cipher_overhead(options) - CHECKSUM_SIZE
+}
+
+// 暗号化によってページが大きくなる分
+fn cipher_overhead(options: &DiskOptions) -> usize {
+    options
+        .cipher
+        .as_ref()
+        .map_or(0, |cipher| cipher.overhead())
+}
+
+/*
+    ページのチェックサムを計算する。
+    ページIDも計算に含めることで、別の位置に書き込まれてしまったページも検出できる。
+*/
+fn checksum(page_id: PageId, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&page_id.to_u64().to_le_bytes());
+    hasher.update(data);
+    hasher.finalize()
+}
 
-    pub fn sync(&mut self) -> io::Result<()> {
-        self.heap_file.flush()?;
-        self.heap_file.sync_all()
+// 新しいデータベースの識別子を乱数で決める。0は記録されていないことを表すので使わない
+fn new_database_id() -> u64 {
+    loop {
+        let database_id = OsRng.next_u64();
+        if database_id != 0 {
+            return database_id;
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::disk::fault::{FaultConfig, FaultyStorage};
+    use crate::disk::storage::MemoryStorage;
+    use std::io::{Seek, SeekFrom, Write};
     use tempfile::NamedTempFile;
 
+    // 暗号化も圧縮もしない場合に、1ページに読み書きできるページデータの大きさ
+    const DATA_SIZE: usize = PAGE_SIZE - CHECKSUM_SIZE;
+
     #[test]
     fn test() {
         let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
         let mut disk = DiskManager::new(data_file).unwrap();
-        let mut hello = Vec::with_capacity(PAGE_SIZE);
+        let mut hello = Vec::with_capacity(DATA_SIZE);
         hello.extend_from_slice(b"hello");
-        hello.resize(PAGE_SIZE, 0);
-        let hello_page_id = disk.allocate_page();
+        hello.resize(DATA_SIZE, 0);
+        let hello_page_id = disk.allocate_page().unwrap();
         disk.write_page_data(hello_page_id, &hello).unwrap();
-        let mut world = Vec::with_capacity(PAGE_SIZE);
+        let mut world = Vec::with_capacity(DATA_SIZE);
         world.extend_from_slice(b"world");
-        world.resize(PAGE_SIZE, 0);
-        let world_page_id = disk.allocate_page();
+        world.resize(DATA_SIZE, 0);
+        let world_page_id = disk.allocate_page().unwrap();
         disk.write_page_data(world_page_id, &world).unwrap();
         drop(disk);
-        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
-        let mut buf = vec![0; PAGE_SIZE];
+        let disk2 = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0; DATA_SIZE];
         disk2.read_page_data(hello_page_id, &mut buf).unwrap();
         assert_eq!(hello, buf);
         disk2.read_page_data(world_page_id, &mut buf).unwrap();
         assert_eq!(world, buf);
     }
+
+    #[test]
+    fn test_deallocate_page() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let page = vec![1u8; DATA_SIZE];
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| {
+                let page_id = disk.allocate_page().unwrap();
+                disk.write_page_data(page_id, &page).unwrap();
+                page_id
+            })
+            .collect();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        assert!(disk.deallocate_page(page_ids[1]).is_err());
+        disk.deallocate_page(page_ids[2]).unwrap();
+        // 空きページの目印と同じ内容で始まるページでも、使っている間は解放できる
+        let mut marked = vec![0u8; DATA_SIZE];
+        marked[..FREE_PAGE_MAGIC.len()].copy_from_slice(&FREE_PAGE_MAGIC);
+        disk.write_page_data(page_ids[0], &marked).unwrap();
+        disk.deallocate_page(page_ids[0]).unwrap();
+        assert_eq!(page_ids[0], disk.allocate_page().unwrap());
+        drop(disk);
+
+        // 開き直しても解放したページが先に再利用される
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[2], disk2.allocate_page().unwrap());
+        let mut buf = vec![1u8; DATA_SIZE];
+        disk2.read_page_data(page_ids[2], &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        drop(disk2);
+
+        let mut disk3 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[1], disk3.allocate_page().unwrap());
+        assert_eq!(PageId(4), disk3.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_deallocate_page_crash() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let page = vec![1u8; DATA_SIZE];
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| {
+                let page_id = disk.allocate_page().unwrap();
+                disk.write_page_data(page_id, &page).unwrap();
+                page_id
+            })
+            .collect();
+        disk.deallocate_page(page_ids[0]).unwrap();
+        disk.sync().unwrap();
+        let offset = PAGE_SIZE as u64 * page_ids[1].to_u64();
+        let mut slot = vec![0u8; PAGE_SIZE];
+        File::open(&data_file_path)
+            .unwrap()
+            .read_exact_at(&mut slot, offset)
+            .unwrap();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+
+        // ヘッダーを書き換えた後、空きページの目印を書き込む前にクラッシュしたことにする
+        OpenOptions::new()
+            .write(true)
+            .open(&data_file_path)
+            .unwrap()
+            .write_all_at(&slot, offset)
+            .unwrap();
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[1], disk2.allocate_page().unwrap());
+        assert_eq!(page_ids[0], disk2.allocate_page().unwrap());
+        assert_eq!(PageId(4), disk2.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_checksum() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let mut hello = vec![0u8; DATA_SIZE];
+        hello[..5].copy_from_slice(b"hello");
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &hello).unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+        // チェックサムはページの中に置かれるので、ページはページサイズの境目に揃って並ぶ
+        assert_eq!(
+            2 * PAGE_SIZE as u64,
+            std::fs::metadata(&data_file_path).unwrap().len()
+        );
+
+        // ページの途中の1バイトを書き換えて壊す
+        let offset = PAGE_SIZE as u64 * page_id.to_u64() + 2;
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(&data_file_path)
+            .unwrap();
+        data_file.seek(SeekFrom::Start(offset)).unwrap();
+        data_file.write_all(b"L").unwrap();
+        let disk2 = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0u8; DATA_SIZE];
+        match disk2.read_page_data(page_id, &mut buf) {
+            Err(Error::Corrupted {
+                page_id: corrupted_page_id,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(page_id, corrupted_page_id);
+                assert_eq!(checksum(page_id, &hello), expected);
+                assert_ne!(expected, actual);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        // ヘッダーやチェックサムを導入する前の形式のファイルを用意する
+        let (mut data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut hello = vec![0u8; PAGE_SIZE];
+        hello[..5].copy_from_slice(b"hello");
+        data_file.write_all(&hello).unwrap();
+        drop(data_file);
+
+        assert!(matches!(
+            DiskManager::open(&data_file_path),
+            Err(Error::NotDatabase)
+        ));
+        let options = DiskOptions {
+            legacy: true,
+            ..Default::default()
+        };
+        let mut disk = DiskManager::open_with(&data_file_path, options).unwrap();
+        assert_eq!(Some(PageId(0)), disk.catalog_root());
+        let mut buf = vec![0u8; PAGE_SIZE];
+        disk.read_page_data(PageId(0), &mut buf).unwrap();
+        assert_eq!(hello, buf);
+        let page_id = disk.allocate_page().unwrap();
+        assert_eq!(PageId(1), page_id);
+        disk.write_page_data(page_id, &hello).unwrap();
+        assert_eq!(
+            2 * PAGE_SIZE as u64,
+            std::fs::metadata(&data_file_path).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        assert_eq!(None, disk.catalog_root());
+        // ヘッダーページは採番されず、読み書きもできない
+        let page_id = disk.allocate_page().unwrap();
+        assert_ne!(HEADER_PAGE_ID, page_id);
+        assert!(disk
+            .write_page_data(HEADER_PAGE_ID, &[0u8; DATA_SIZE])
+            .is_err());
+        disk.set_catalog_root(page_id).unwrap();
+        let database_id = disk.database_id();
+        assert_ne!(0, database_id);
+        drop(disk);
+
+        let disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(Some(page_id), disk2.catalog_root());
+        assert_eq!(database_id, disk2.database_id());
+        drop(disk2);
+        // 別に作ったデータベースとは識別子が違う
+        let other =
+            DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        assert_ne!(database_id, other.database_id());
+
+        // 知らないフラグが立っているファイルは開けない
+        let mut header = Header::new(PAGE_SIZE);
+        header.catalog_root = page_id;
+        header.flags = 1 << 31;
+        let data_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&data_file_path)
+            .unwrap();
+        data_file.write_all_at(&header.encode(), 0).unwrap();
+        assert!(DiskManager::open(&data_file_path).is_err());
+
+        // 対応していないバージョンのファイルは開けない
+        header.version = FORMAT_VERSION + 1;
+        header.flags = 0;
+        data_file.write_all_at(&header.encode(), 0).unwrap();
+        assert!(matches!(
+            DiskManager::open(&data_file_path),
+            Err(Error::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let mut disk =
+            DiskManager::with_storage(MemoryStorage::new(), DiskOptions::default()).unwrap();
+        let mut hello = vec![0u8; DATA_SIZE];
+        hello[..5].copy_from_slice(b"hello");
+        let hello_page_id = disk.allocate_page().unwrap();
+        // 確保しただけのページは0で埋められている
+        let mut buf = vec![1u8; DATA_SIZE];
+        disk.read_page_data(hello_page_id, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        disk.write_page_data(hello_page_id, &hello).unwrap();
+        disk.sync().unwrap();
+        disk.read_page_data(hello_page_id, &mut buf).unwrap();
+        assert_eq!(hello, buf);
+        disk.deallocate_page(hello_page_id).unwrap();
+        assert_eq!(hello_page_id, disk.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_write_pages_data() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let pages: Vec<_> = (0..3u8).map(|i| vec![i + 1; DATA_SIZE]).collect();
+        let first_page_id = disk.allocate_page().unwrap();
+        for _ in 1..pages.len() {
+            disk.allocate_page().unwrap();
+        }
+        let data: Vec<&[u8]> = pages.iter().map(|page| page.as_slice()).collect();
+        disk.write_pages_data(first_page_id, &data).unwrap();
+        assert!(disk.write_pages_data(HEADER_PAGE_ID, &data).is_err());
+        drop(disk);
+
+        let disk2 = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0u8; DATA_SIZE];
+        for (i, page) in pages.iter().enumerate() {
+            let page_id = PageId(first_page_id.to_u64() + i as u64);
+            disk2.read_page_data(page_id, &mut buf).unwrap();
+            assert_eq!(page, &buf);
+        }
+        // 連続したページはまとめて読み込める
+        let mut bufs = vec![vec![0u8; DATA_SIZE]; pages.len()];
+        let mut data: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| buf.as_mut_slice()).collect();
+        disk2.read_pages_data(first_page_id, &mut data).unwrap();
+        assert_eq!(pages, bufs);
+        let mut data: Vec<&mut [u8]> = bufs.iter_mut().map(|buf| buf.as_mut_slice()).collect();
+        assert!(disk2
+            .read_pages_data(PageId(first_page_id.to_u64() + 1), &mut data)
+            .is_err());
+    }
+
+    #[test]
+    fn test_page_size() {
+        for page_size in [1024, 4096, 16 * 1024, 32 * 1024] {
+            let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+            let options = DiskOptions {
+                page_size,
+                ..Default::default()
+            };
+            let mut disk = DiskManager::new_with(data_file, options).unwrap();
+            assert_eq!(page_size, disk.page_size());
+            let data_size = disk.data_size();
+            assert_eq!(page_size - CHECKSUM_SIZE, data_size);
+            let mut hello = vec![0u8; data_size];
+            hello[..5].copy_from_slice(b"hello");
+            hello[data_size - 5..].copy_from_slice(b"world");
+            let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+            for &page_id in &page_ids {
+                disk.write_page_data(page_id, &hello).unwrap();
+            }
+            assert!(disk
+                .write_page_data(page_ids[0], &[0u8; PAGE_SIZE / 2])
+                .is_err());
+            disk.deallocate_page(page_ids[1]).unwrap();
+            drop(disk);
+
+            // 開き直すとヘッダーに記録されたページサイズが使われる
+            let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+            assert_eq!(page_size, disk2.page_size());
+            let mut buf = vec![0u8; data_size];
+            disk2.read_page_data(page_ids[2], &mut buf).unwrap();
+            assert_eq!(hello, buf);
+            assert_eq!(page_ids[1], disk2.allocate_page().unwrap());
+        }
+
+        let options = DiskOptions {
+            page_size: 3000,
+            ..Default::default()
+        };
+        assert!(matches!(
+            DiskManager::with_storage(MemoryStorage::new(), options),
+            Err(Error::InvalidPageSize(3000))
+        ));
+    }
+
+    #[test]
+    fn test_compression() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let options = DiskOptions {
+            compression: true,
+            ..Default::default()
+        };
+        let mut disk = DiskManager::new_with(data_file, options).unwrap();
+        // 繰り返しの多いテキストが前半にだけ入っているページ
+        let pages: Vec<Vec<u8>> = (0..16)
+            .map(|i| {
+                let mut page = format!("row {}: the quick brown fox; ", i)
+                    .repeat(64)
+                    .into_bytes();
+                page.resize(PAGE_SIZE, 0);
+                page
+            })
+            .collect();
+        let page_ids: Vec<_> = pages
+            .iter()
+            .map(|page| {
+                let page_id = disk.allocate_page().unwrap();
+                disk.write_page_data(page_id, page).unwrap();
+                page_id
+            })
+            .collect();
+        disk.sync().unwrap();
+        let stats = disk.compression_stats().unwrap();
+        assert_eq!(16, stats.pages);
+        assert_eq!(16 * PAGE_SIZE as u64, stats.logical_bytes);
+        assert!(stats.saved_bytes() > 0);
+        assert!(stats.ratio() < 0.5);
+        drop(disk);
+        // 圧縮しない場合よりもファイルが小さくなる
+        let file_size = std::fs::metadata(&data_file_path).unwrap().len();
+        assert!(file_size < PAGE_SIZE as u64 * 17 / 2);
+
+        // 圧縮するかどうかはヘッダーに記録されている
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert!(disk2.compression_stats().is_some());
+        let mut buf = vec![0u8; PAGE_SIZE];
+        for (page_id, page) in page_ids.iter().zip(&pages) {
+            disk2.read_page_data(*page_id, &mut buf).unwrap();
+            assert_eq!(page, &buf);
+        }
+        // 圧縮できないページは別の場所にそのまま書き込まれる
+        let mut state = 1u64;
+        let noise: Vec<u8> = (0..PAGE_SIZE)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect();
+        disk2.write_page_data(page_ids[3], &noise).unwrap();
+        disk2.deallocate_page(page_ids[5]).unwrap();
+        disk2.sync().unwrap();
+        drop(disk2);
+
+        let mut disk3 = DiskManager::open(&data_file_path).unwrap();
+        disk3.read_page_data(page_ids[3], &mut buf).unwrap();
+        assert_eq!(noise, buf);
+        disk3.read_page_data(page_ids[4], &mut buf).unwrap();
+        assert_eq!(pages[4], buf);
+        assert_eq!(page_ids[5], disk3.allocate_page().unwrap());
+        disk3.read_page_data(page_ids[5], &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_compression_crash() {
+        let page = |version: u8| {
+            let mut page = format!("row {}: the quick brown fox; ", version)
+                .repeat(64)
+                .into_bytes();
+            page.resize(PAGE_SIZE, 0);
+            page
+        };
+        let options = DiskOptions {
+            compression: true,
+            ..Default::default()
+        };
+        for seed in 0..20 {
+            let storage = FaultyStorage::new(FaultConfig {
+                seed,
+                torn_writes: true,
+                ..Default::default()
+            });
+            let mut disk = DiskManager::with_storage(storage.clone(), options.clone()).unwrap();
+            let page_id = disk.allocate_page().unwrap();
+            disk.write_page_data(page_id, &page(1)).unwrap();
+            disk.sync().unwrap();
+
+            // 同じ大きさに圧縮されるページでも、同期した対応表が指している領域には上書きしない
+            disk.write_page_data(page_id, &page(2)).unwrap();
+            storage.crash();
+            drop(disk);
+
+            let disk = DiskManager::with_storage(storage.restart(), options.clone()).unwrap();
+            let mut buf = vec![0u8; PAGE_SIZE];
+            disk.read_page_data(page_id, &mut buf).unwrap();
+            assert_eq!(page(1), buf, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_encryption() {
+        use crate::disk::cipher::ChaCha20Poly1305Cipher;
+
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let options = |key: u8| DiskOptions {
+            cipher: Some(Arc::new(ChaCha20Poly1305Cipher::new(&[key; 32]))),
+            ..Default::default()
+        };
+        let mut disk = DiskManager::new_with(data_file, options(1)).unwrap();
+        // 暗号化で増える分もページの中に置くので、読み書きできる大きさはその分小さくなる
+        let data_size = disk.data_size();
+        assert_eq!(PAGE_SIZE - 28 - CHECKSUM_SIZE, data_size);
+        let mut secret = vec![0u8; data_size];
+        secret[..12].copy_from_slice(b"hello secret");
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &secret).unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+        // ファイルには平文が残らない
+        let contents = std::fs::read(&data_file_path).unwrap();
+        assert!(!contents.windows(12).any(|w| w == b"hello secret"));
+
+        let disk2 = DiskManager::open_with(&data_file_path, options(1)).unwrap();
+        let mut buf = vec![0u8; data_size];
+        disk2.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(secret, buf);
+        drop(disk2);
+        assert!(matches!(
+            DiskManager::open_with(&data_file_path, options(2)),
+            Err(Error::WrongKey)
+        ));
+        assert!(matches!(
+            DiskManager::open(&data_file_path),
+            Err(Error::KeyRequired)
+        ));
+
+        // チェックサムを合わせて書き換えても、認証タグで検出できる
+        let offset = PAGE_SIZE as u64 * page_id.to_u64();
+        let mut slot = contents[offset as usize..offset as usize + PAGE_SIZE].to_vec();
+        slot[20] ^= 1;
+        let page_len = PAGE_SIZE - CHECKSUM_SIZE;
+        let crc = checksum(page_id, &slot[..page_len]);
+        slot[page_len..].copy_from_slice(&crc.to_le_bytes());
+        let mut data_file = OpenOptions::new()
+            .write(true)
+            .open(&data_file_path)
+            .unwrap();
+        data_file.seek(SeekFrom::Start(offset)).unwrap();
+        data_file.write_all(&slot).unwrap();
+        let disk3 = DiskManager::open_with(&data_file_path, options(1)).unwrap();
+        assert!(matches!(
+            disk3.read_page_data(page_id, &mut buf),
+            Err(Error::Unauthenticated(id)) if id == page_id
+        ));
+
+        // 圧縮と組み合わせることもできる
+        let options = DiskOptions {
+            compression: true,
+            ..options(3)
+        };
+        let storage = MemoryStorage::new();
+        let mut disk4 = DiskManager::with_storage(storage, options).unwrap();
+        let page_id = disk4.allocate_page().unwrap();
+        let mut compressed = vec![0u8; disk4.data_size()];
+        compressed[..12].copy_from_slice(b"hello secret");
+        disk4.write_page_data(page_id, &compressed).unwrap();
+        let mut buf = vec![1u8; disk4.data_size()];
+        disk4.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(compressed, buf);
+
+        // 暗号化していても、解放済みのページは二重に解放できない
+        let options = DiskOptions {
+            cipher: Some(Arc::new(ChaCha20Poly1305Cipher::new(&[4; 32]))),
+            ..Default::default()
+        };
+        let mut disk5 = DiskManager::with_storage(MemoryStorage::new(), options).unwrap();
+        let page_id = disk5.allocate_page().unwrap();
+        disk5.write_page_data(page_id, &secret).unwrap();
+        disk5.deallocate_page(page_id).unwrap();
+        assert!(disk5.deallocate_page(page_id).is_err());
+        assert_eq!(page_id, disk5.allocate_page().unwrap());
+        assert_ne!(page_id, disk5.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_lock_and_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let read_only = DiskOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        // 読み込み専用ではファイルを作らない
+        assert!(DiskManager::open_with(&path, read_only.clone()).is_err());
+        assert!(!path.exists());
+
+        let mut disk = DiskManager::open(&path).unwrap();
+        let mut hello = vec![0u8; DATA_SIZE];
+        hello[..5].copy_from_slice(b"hello");
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &hello).unwrap();
+        disk.sync().unwrap();
+        // 書き込み中のファイルは、他からは開けない
+        assert!(matches!(DiskManager::open(&path), Err(Error::Locked)));
+        assert!(matches!(
+            DiskManager::open_with(&path, read_only.clone()),
+            Err(Error::Locked)
+        ));
+        drop(disk);
+
+        // 読み込み専用なら同時に開ける
+        let mut reader = DiskManager::open_with(&path, read_only.clone()).unwrap();
+        let reader2 = DiskManager::open_with(&path, read_only).unwrap();
+        assert!(matches!(DiskManager::open(&path), Err(Error::Locked)));
+        let mut buf = vec![0u8; DATA_SIZE];
+        reader2.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(hello, buf);
+        assert!(matches!(
+            reader.write_page_data(page_id, &hello),
+            Err(Error::ReadOnly)
+        ));
+        assert!(matches!(reader.allocate_page(), Err(Error::ReadOnly)));
+        reader.sync().unwrap();
+    }
+
+    #[test]
+    fn test_compact() {
+        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let page_ids: Vec<_> = (0..10).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &[page_ids[0], page_ids[2], page_ids[4]] {
+            disk.deallocate_page(page_id).unwrap();
+        }
+        // 使われているページを、先頭8バイトに書いたPageIdで順に繋いでおく
+        let live: Vec<_> = page_ids
+            .iter()
+            .copied()
+            .filter(|page_id| ![1, 3, 5].contains(&page_id.to_u64()))
+            .collect();
+        for (i, &page_id) in live.iter().enumerate() {
+            let mut data = vec![page_id.to_u64() as u8; DATA_SIZE];
+            let next = live.get(i + 1).copied().unwrap_or(PageId::INVALID_PAGE_ID);
+            data[..8].copy_from_slice(next.as_bytes());
+            disk.write_page_data(page_id, &data).unwrap();
+        }
+        disk.set_catalog_root(page_ids[9]).unwrap();
+
+        let stats = disk
+            .compact(|data, relocation| {
+                let next = relocation.get(PageId::from(&data[..8]));
+                data[..8].copy_from_slice(next.as_bytes());
+            })
+            .unwrap();
+        let slot_size = PAGE_SIZE as u64;
+        assert_eq!(7, stats.live_pages);
+        assert_eq!(3, stats.moved_pages);
+        assert_eq!(3, stats.freed_pages);
+        assert_eq!(3 * slot_size, stats.reclaimed_bytes);
+        drop(disk);
+        assert_eq!(
+            8 * slot_size,
+            std::fs::metadata(&data_file_path).unwrap().len()
+        );
+
+        // 移動したページを指す参照も書き換わっている
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(Some(PageId(1)), disk2.catalog_root());
+        let mut buf = vec![0u8; DATA_SIZE];
+        let mut page_id = live[0];
+        let mut visited = vec![];
+        while let Some(current) = page_id.valid() {
+            disk2.read_page_data(current, &mut buf).unwrap();
+            visited.push((current.to_u64(), buf[8]));
+            page_id = PageId::from(&buf[..8]);
+        }
+        assert_eq!(
+            vec![(2, 2), (4, 4), (6, 6), (7, 7), (5, 8), (3, 9), (1, 10)],
+            visited
+        );
+        // 空きページはなくなっているので、末尾に新しいページが作られる
+        assert_eq!(PageId(8), disk2.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_compact_crash() {
+        let mut crash_after_writes = 1;
+        loop {
+            let storage = FaultyStorage::new(FaultConfig {
+                seed: crash_after_writes,
+                ..Default::default()
+            });
+            let mut disk =
+                DiskManager::with_storage(storage.clone(), DiskOptions::default()).unwrap();
+            let page_ids: Vec<_> = (0..10).map(|_| disk.allocate_page().unwrap()).collect();
+            for &page_id in &[page_ids[0], page_ids[2], page_ids[4]] {
+                disk.deallocate_page(page_id).unwrap();
+            }
+            // 使われているページを、カタログから後ろ向きに先頭8バイトに書いたPageIdで繋いでおく
+            let mut prev = PageId::INVALID_PAGE_ID;
+            for &page_id in &page_ids {
+                if disk.is_free_page(page_id) {
+                    continue;
+                }
+                let mut data = vec![page_id.to_u64() as u8; DATA_SIZE];
+                data[..8].copy_from_slice(prev.as_bytes());
+                disk.write_page_data(page_id, &data).unwrap();
+                prev = page_id;
+            }
+            disk.set_catalog_root(prev).unwrap();
+            disk.sync().unwrap();
+
+            // コンパクションの途中のどこでクラッシュしても、開き直せばすべてのページを辿れる
+            storage.set_crash_after_writes(Some(crash_after_writes));
+            let result = disk.compact(|data, relocation| {
+                let prev = relocation.get(PageId::from(&data[..8]));
+                data[..8].copy_from_slice(prev.as_bytes());
+            });
+            let crashed = storage.is_crashed();
+            assert_eq!(crashed, result.is_err());
+            drop(disk);
+
+            let disk =
+                DiskManager::with_storage(storage.restart(), DiskOptions::default()).unwrap();
+            let mut buf = vec![0u8; DATA_SIZE];
+            let mut page_id = disk.catalog_root().unwrap();
+            let mut visited = vec![];
+            loop {
+                disk.read_page_data(page_id, &mut buf).unwrap();
+                visited.push(buf[8]);
+                match PageId::from(&buf[..8]).valid() {
+                    Some(prev) => page_id = prev,
+                    None => break,
+                }
+            }
+            assert_eq!(vec![10, 9, 8, 7, 6, 4, 2], visited);
+            // 書き写していたページは残らない
+            let size = storage.size().unwrap();
+            assert!(size == 8 * PAGE_SIZE as u64 || size == 11 * PAGE_SIZE as u64);
+            if !crashed {
+                assert_eq!(8 * PAGE_SIZE as u64, size);
+                break;
+            }
+            crash_after_writes += 1;
+        }
+    }
 }
@@ -0,0 +1,292 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::storage::Storage;
+
+// ジャーナルの先頭に書かれる目印
+const JOURNAL_MAGIC: [u8; 8] = *b"ARTSDWB\0";
+// 目印と世代番号
+const JOURNAL_HEADER_SIZE: u64 = 16;
+// 世代番号、書き込み先のオフセット、長さ、チェックサム
+const RECORD_HEADER_SIZE: usize = 24;
+// ジャーナルがこの大きさを超えそうになったら、書き込み先を永続化してジャーナルを空にする
+const JOURNAL_CAPACITY: u64 = 4 << 20;
+
+/*
+    書き込みを本来の位置に書く前に、まずジャーナル(ダブルライト領域)に書いて永続化するストレージ。
+    本来の位置への書き込みが途中でちぎれても、開き直したときにジャーナルに残っている写しから復元できる。
+
+    ジャーナルは世代番号の付いた記録を先頭から並べたもので、書き込み先をsyncするたびに世代番号を上げて空にする。
+    開くときは今の世代の記録を順に読み、書き込み先の内容が記録と違っていれば記録の内容で上書きする。
+*/
+pub struct DoubleWriteStorage {
+    home: Box<dyn Storage>,
+    journal: Box<dyn Storage>,
+    generation: u64,
+    // 次の記録を書き込む位置
+    journal_end: u64,
+    // 開いたときにジャーナルから復元した書き込みの数
+    restored_writes: u64,
+}
+
+impl DoubleWriteStorage {
+    // homeに書き込むストレージを作る。journalに前回の書き込みが残っていれば、homeを復元する
+    pub fn new(home: impl Storage + 'static, journal: impl Storage + 'static) -> io::Result<Self> {
+        let mut storage = Self {
+            home: Box::new(home),
+            journal: Box::new(journal),
+            generation: 0,
+            journal_end: JOURNAL_HEADER_SIZE,
+            restored_writes: 0,
+        };
+        storage.recover()?;
+        Ok(storage)
+    }
+
+    pub fn restored_writes(&self) -> u64 {
+        self.restored_writes
+    }
+
+    fn recover(&mut self) -> io::Result<()> {
+        let journal_size = self.journal.size()?;
+        let mut header = [0u8; JOURNAL_HEADER_SIZE as usize];
+        if journal_size >= JOURNAL_HEADER_SIZE {
+            self.journal.read_at(0, &mut header)?;
+        }
+        // 目印が壊れていれば、復元すべき記録はないものとして扱う
+        if header[..8] == JOURNAL_MAGIC {
+            self.generation = u64::from_le_bytes(header[8..].try_into().unwrap());
+            let mut offset = JOURNAL_HEADER_SIZE;
+            while let Some((home_offset, data)) = self.read_record(offset, journal_size)? {
+                offset += (RECORD_HEADER_SIZE + data.len()) as u64;
+                let mut current = vec![0u8; data.len()];
+                let end = home_offset + data.len() as u64;
+                if end <= self.home.size()? {
+                    self.home.read_at(home_offset, &mut current)?;
+                    if current == data {
+                        continue;
+                    }
+                }
+                self.home.write_at(home_offset, &data)?;
+                self.restored_writes += 1;
+            }
+            if self.restored_writes > 0 {
+                self.home.sync()?;
+            }
+        }
+        self.reset()
+    }
+
+    // offsetにある今の世代の記録を読む。記録がないか、途中で切れている場合はNoneを返す
+    fn read_record(&self, offset: u64, journal_size: u64) -> io::Result<Option<(u64, Vec<u8>)>> {
+        if offset + RECORD_HEADER_SIZE as u64 > journal_size {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.journal.read_at(offset, &mut header)?;
+        let generation = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let home_offset = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as u64;
+        let expected = u32::from_le_bytes(header[20..24].try_into().unwrap());
+        if generation != self.generation || offset + RECORD_HEADER_SIZE as u64 + len > journal_size
+        {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; len as usize];
+        self.journal
+            .read_at(offset + RECORD_HEADER_SIZE as u64, &mut data)?;
+        if record_checksum(&header[..20], &data) != expected {
+            return Ok(None);
+        }
+        Ok(Some((home_offset, data)))
+    }
+
+    // 世代番号を上げて、それまでの記録を無効にする
+    fn reset(&mut self) -> io::Result<()> {
+        self.generation += 1;
+        let mut header = [0u8; JOURNAL_HEADER_SIZE as usize];
+        header[..8].copy_from_slice(&JOURNAL_MAGIC);
+        header[8..].copy_from_slice(&self.generation.to_le_bytes());
+        self.journal.write_at(0, &header)?;
+        self.journal.sync()?;
+        self.journal_end = JOURNAL_HEADER_SIZE;
+        Ok(())
+    }
+}
+
+// ジャーナルのパス。データベースファイルのパスの後ろに".dwb"を付ける
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".dwb");
+    PathBuf::from(path)
+}
+
+fn record_checksum(header: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+impl Storage for DoubleWriteStorage {
+    fn size(&self) -> io::Result<u64> {
+        self.home.size()
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        self.home.read_at(offset, data)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.write_batch(&[(offset, data)])
+    }
+
+    // すべての書き込みの写しをジャーナルに並べて一度だけ永続化し、それから本来の位置に書き込む
+    fn write_batch(&mut self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        let records_size: u64 = writes
+            .iter()
+            .map(|(_, data)| (RECORD_HEADER_SIZE + data.len()) as u64)
+            .sum();
+        if self.journal_end > JOURNAL_HEADER_SIZE
+            && self.journal_end + records_size > JOURNAL_CAPACITY
+        {
+            // ジャーナルの記録が要らなくなるように、先にそれまでの書き込みを永続化する
+            self.home.sync()?;
+            self.reset()?;
+        }
+        let mut records = Vec::with_capacity(records_size as usize);
+        for &(offset, data) in writes {
+            let start = records.len();
+            records.extend_from_slice(&self.generation.to_le_bytes());
+            records.extend_from_slice(&offset.to_le_bytes());
+            records.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            let checksum = record_checksum(&records[start..], data);
+            records.extend_from_slice(&checksum.to_le_bytes());
+            records.extend_from_slice(data);
+        }
+        self.journal.write_at(self.journal_end, &records)?;
+        self.journal.sync()?;
+        self.journal_end += records_size;
+        // 写しが永続化されてから、本来の位置に書き込む
+        self.home.write_batch(writes)
+    }
+
+    fn allocate(&mut self, size: u64) -> io::Result<()> {
+        self.home.allocate(size)
+    }
+
+    // 切り詰めた範囲への記録が復元で書き戻されないように、先にジャーナルを空にしておく
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        self.home.sync()?;
+        self.reset()?;
+        self.home.truncate(size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.home.sync()?;
+        self.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{
+        DiskManager, DiskOptions, FaultConfig, FaultyStorage, MemoryStorage, PageId,
+    };
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    // syncが呼ばれた回数を数えるストレージ
+    struct CountingStorage {
+        inner: MemoryStorage,
+        syncs: Arc<AtomicU64>,
+    }
+
+    impl Storage for CountingStorage {
+        fn size(&self) -> io::Result<u64> {
+            self.inner.size()
+        }
+        fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+            self.inner.read_at(offset, data)
+        }
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+            self.inner.write_at(offset, data)
+        }
+        fn allocate(&mut self, size: u64) -> io::Result<()> {
+            self.inner.allocate(size)
+        }
+        fn truncate(&mut self, size: u64) -> io::Result<()> {
+            self.inner.truncate(size)
+        }
+        fn sync(&mut self) -> io::Result<()> {
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+            self.inner.sync()
+        }
+    }
+
+    #[test]
+    fn test_batch_syncs_journal_once() {
+        let syncs = Arc::new(AtomicU64::new(0));
+        let journal = CountingStorage {
+            inner: MemoryStorage::new(),
+            syncs: Arc::clone(&syncs),
+        };
+        let storage = DoubleWriteStorage::new(MemoryStorage::new(), journal).unwrap();
+        let mut disk = DiskManager::with_storage(storage, DiskOptions::default()).unwrap();
+        let data_size = disk.data_size();
+        for _ in 0..4 {
+            disk.allocate_page().unwrap();
+        }
+        disk.sync().unwrap();
+
+        let page = vec![3u8; data_size];
+        let before = syncs.load(Ordering::SeqCst);
+        disk.write_page_runs(&[(PageId(1), &[&page, &page]), (PageId(4), &[&page])])
+            .unwrap();
+        assert_eq!(before + 1, syncs.load(Ordering::SeqCst));
+        let mut buf = vec![0u8; data_size];
+        for page_id in [1, 2, 4] {
+            disk.read_page_data(PageId(page_id), &mut buf).unwrap();
+            assert_eq!(page, buf);
+        }
+    }
+
+    #[test]
+    fn test_restore_torn_writes() {
+        for seed in 0..20 {
+            let config = FaultConfig {
+                seed,
+                torn_writes: true,
+                ..Default::default()
+            };
+            let home = FaultyStorage::new(config);
+            let journal = FaultyStorage::new(FaultConfig::default());
+            let storage = DoubleWriteStorage::new(home.clone(), journal.clone()).unwrap();
+            let mut disk = DiskManager::with_storage(storage, DiskOptions::default()).unwrap();
+            let data_size = disk.data_size();
+            let page_ids: Vec<_> = (0..4)
+                .map(|_| {
+                    let page_id = disk.allocate_page().unwrap();
+                    disk.write_page_data(page_id, &vec![1; data_size]).unwrap();
+                    page_id
+                })
+                .collect();
+            disk.sync().unwrap();
+            for &page_id in &page_ids {
+                disk.write_page_data(page_id, &vec![2; data_size]).unwrap();
+            }
+            // 本来の位置への書き込みは、消えたりちぎれたりする
+            home.crash();
+            drop(disk);
+
+            let storage = DoubleWriteStorage::new(home.restart(), journal.restart()).unwrap();
+            let disk = DiskManager::with_storage(storage, DiskOptions::default()).unwrap();
+            let mut buf = vec![0u8; data_size];
+            for &page_id in &page_ids {
+                disk.read_page_data(page_id, &mut buf).unwrap();
+                assert!(buf.iter().all(|&b| b == 2), "seed {}", seed);
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::storage::Storage;
+
+/*
+    障害の起こし方を決める設定。
+    同じseedを与えれば、書き込みの失敗やクラッシュ時にどの書き込みが残るかが毎回同じになる。
+*/
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    pub seed: u64,
+    // 書き込みがI/Oエラーで失敗する確率(0.0〜1.0)
+    pub write_error_rate: f64,
+    // この回数だけ書き込みが成功した直後にクラッシュさせる
+    pub crash_after_writes: Option<u64>,
+    // クラッシュしたときに、同期されていない書き込みが途中までしか書かれない(ちぎれる)ことを許すかどうか
+    pub torn_writes: bool,
+}
+
+// 同期されていない書き込み
+enum PendingWrite {
+    Write { offset: u64, data: Vec<u8> },
+    Allocate { size: u64 },
+    Truncate { size: u64 },
+}
+
+struct State {
+    config: FaultConfig,
+    rng: XorShift,
+    // syncによって永続化された内容
+    durable: Vec<u8>,
+    // 読み込み時に見える、同期されていない書き込みも反映した内容
+    volatile: Vec<u8>,
+    pending: Vec<PendingWrite>,
+    writes: u64,
+    crashed: bool,
+}
+
+/*
+    クラッシュ時の一貫性をテストするためのストレージ。
+    書き込みのI/Oエラー、クラッシュによる同期されていない書き込みの消失、ページの書き込みのちぎれを、
+    シードから決まるスケジュールに従って起こす。
+    cloneしたものは同じ内容を共有するので、DiskManagerに渡したあとでもクラッシュを起こしたり、
+    restartで作ったストレージを使ってクラッシュ後の内容を開き直したりできる。
+*/
+#[derive(Clone)]
+pub struct FaultyStorage {
+    state: Arc<Mutex<State>>,
+}
+
+impl FaultyStorage {
+    pub fn new(config: FaultConfig) -> Self {
+        let rng = XorShift::new(config.seed);
+        let state = State {
+            config,
+            rng,
+            durable: vec![],
+            volatile: vec![],
+            pending: vec![],
+            writes: 0,
+            crashed: false,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn set_write_error_rate(&self, write_error_rate: f64) {
+        self.state().config.write_error_rate = write_error_rate;
+    }
+
+    pub fn set_crash_after_writes(&self, crash_after_writes: Option<u64>) {
+        let mut state = self.state();
+        state.writes = 0;
+        state.config.crash_after_writes = crash_after_writes;
+    }
+
+    /*
+        電源断を模擬する。
+        同期されていない書き込みはそれぞれ、消える・すべて残る・途中まで残る(torn_writesの場合)のいずれかになる。
+        クラッシュした後は、restartするまですべての操作がエラーになる。
+    */
+    pub fn crash(&self) {
+        self.state().crash();
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.state().crashed
+    }
+
+    // クラッシュ後に再起動したものとして、永続化された内容から使い直せるストレージを返す
+    pub fn restart(&self) -> Self {
+        let mut state = self.state();
+        if !state.crashed {
+            state.crash();
+        }
+        state.crashed = false;
+        state.writes = 0;
+        state.config.crash_after_writes = None;
+        Self {
+            state: Arc::clone(&self.state),
+        }
+    }
+}
+
+impl State {
+    fn check_crashed(&self) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("storage has crashed"));
+        }
+        Ok(())
+    }
+
+    // 書き込みを失敗させるかどうかを決める
+    fn inject_write_error(&mut self) -> io::Result<()> {
+        self.check_crashed()?;
+        if self.rng.next_f64() < self.config.write_error_rate {
+            return Err(io::Error::other("injected write error"));
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, pending: PendingWrite) {
+        apply(&mut self.volatile, &pending, None);
+        self.pending.push(pending);
+        self.writes += 1;
+        if Some(self.writes) == self.config.crash_after_writes {
+            self.crash();
+        }
+    }
+
+    fn crash(&mut self) {
+        for pending in std::mem::take(&mut self.pending) {
+            let len = match &pending {
+                PendingWrite::Write { data, .. } => data.len(),
+                PendingWrite::Allocate { .. } | PendingWrite::Truncate { .. } => 0,
+            };
+            match self.rng.next_u64() % 3 {
+                // 書き込みが失われる
+                0 => continue,
+                // 途中までしか書かれない
+                1 if self.config.torn_writes && len > 1 => {
+                    let torn_len = 1 + (self.rng.next_u64() as usize) % (len - 1);
+                    apply(&mut self.durable, &pending, Some(torn_len));
+                }
+                // すべて書かれる
+                _ => apply(&mut self.durable, &pending, None),
+            }
+        }
+        self.volatile = self.durable.clone();
+        self.crashed = true;
+    }
+}
+
+// 書き込みをdataに反映する。lenを指定すると先頭のlenバイトだけが書かれる
+fn apply(data: &mut Vec<u8>, pending: &PendingWrite, len: Option<usize>) {
+    match pending {
+        PendingWrite::Write { offset, data: src } => {
+            let src = &src[..len.unwrap_or(src.len())];
+            let start = *offset as usize;
+            let end = start + src.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[start..end].copy_from_slice(src);
+        }
+        PendingWrite::Allocate { size } => {
+            if data.len() < *size as usize {
+                data.resize(*size as usize, 0);
+            }
+        }
+        PendingWrite::Truncate { size } => data.truncate(*size as usize),
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn size(&self) -> io::Result<u64> {
+        let state = self.state();
+        state.check_crashed()?;
+        Ok(state.volatile.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let state = self.state();
+        state.check_crashed()?;
+        let start = offset as usize;
+        let src = state
+            .volatile
+            .get(start..start + data.len())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+            })?;
+        data.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state();
+        state.inject_write_error()?;
+        state.record(PendingWrite::Write {
+            offset,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.inject_write_error()?;
+        state.record(PendingWrite::Allocate { size });
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.inject_write_error()?;
+        state.record(PendingWrite::Truncate { size });
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_crashed()?;
+        state.durable = state.volatile.clone();
+        state.pending.clear();
+        Ok(())
+    }
+}
+
+// 障害のスケジュールを決めるための簡単な疑似乱数生成器(xorshift64*)
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // 状態が0だと同じ値しか出なくなるので、適当な値を混ぜておく
+        Self((seed ^ 0x9e37_79b9_7f4a_7c15).max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
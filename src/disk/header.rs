@@ -0,0 +1,123 @@
+use std::mem::size_of;
+use std::ops::Range;
+
+use zerocopy::{AsBytes, FromBytes};
+
+use super::page::PageId;
+
+// ファイルの先頭に書かれる、このデータベースのファイルであることを示す目印
+pub const MAGIC: [u8; 8] = *b"ARTSDB\0\0";
+/*
+    ファイル形式のバージョン。互換性のない変更を加えたときに上げる。
+    後から加えたヘッダーの項目は0であれば記録されていないものとして扱うので、項目を加えるだけならバージョンを上げなくてよい。
+*/
+pub const FORMAT_VERSION: u32 = 1;
+/*
+    ヘッダーが使う、先頭ページの先頭の領域。
+    チェックサムはこの領域全体から計算するので、新しい項目を加えても前のプログラムで確かめられる。
+*/
+pub const HEADER_AREA_SIZE: usize = 512;
+// ヘッダーを置くために予約されているページ
+pub const HEADER_PAGE_ID: PageId = PageId(0);
+// ページを圧縮して保存していることを示すフラグ
+pub const FLAG_COMPRESSED: u32 = 1;
+// ページを暗号化して保存していることを示すフラグ
+pub const FLAG_ENCRYPTED: u32 = 2;
+// 知っているフラグ。これ以外のフラグが立っているファイルは開けない
+pub const SUPPORTED_FLAGS: u32 = FLAG_COMPRESSED | FLAG_ENCRYPTED;
+// 鍵が正しいかを確かめるために暗号化しておく平文
+pub const KEY_CHECK_PLAINTEXT: [u8; 16] = *b"ARTSDB KEYCHECK\0";
+
+/*
+    データベースファイルの先頭ページに置かれるヘッダー。
+    ファイルを開くときに最初に読み込まれ、ファイル形式の確認と、
+    空きページのリストやカタログの位置を知るために使われる。
+*/
+#[derive(Debug, Clone, Copy, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub page_size: u32,
+    pub flags: u32,
+    // checksumを0にした状態のヘッダーの領域から計算したチェックサム
+    pub checksum: u32,
+    // 空きページのリストの先頭
+    pub free_list_head: PageId,
+    // カタログ(最初に辿るB-treeのメタページ)の位置
+    pub catalog_root: PageId,
+    // ページを圧縮している場合に、ページの対応表が保存されている位置。0であればまだ保存されていない
+    pub page_map_offset: u64,
+    // 暗号化している場合に、KEY_CHECK_PLAINTEXTを暗号化したもの。後ろの余った部分は0で埋める
+    pub key_check: [u8; 64],
+    /*
+        空きページのリストで、free_list_headの次にある空きページ。HEADER_PAGE_IDであれば記録されていない。
+        ページを解放するときに先頭のページより先に書き込むので、目印を書き込む前にクラッシュしてもリストが途切れない。
+    */
+    pub free_list_next: PageId,
+    // セグメントファイルに分けて保存している場合の、1セグメントのページ数。0であれば分けていない
+    pub segment_pages: u64,
+    /*
+        データベースを作ったときに乱数で決める識別子。0であれば記録されていない。
+        ファイルの外に保存したものが、このデータベースのものかを確かめるために使う。
+    */
+    pub database_id: u64,
+    /*
+        コンパクションで、詰めた後のページを書き写しているファイル上の位置(ページ単位)。0であればコンパクションしていない。
+        compact_endが0の間はまだ確定していないので、開き直すとこの位置から後ろを切り詰めて元に戻す。
+    */
+    pub compact_staging: u64,
+    // 確定したコンパクションの、詰めた後のページ数。開き直すと書き写したページを本来の位置に写し終える
+    pub compact_end: u64,
+}
+
+impl Header {
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size: page_size as u32,
+            flags: 0,
+            checksum: 0,
+            free_list_head: PageId::INVALID_PAGE_ID,
+            catalog_root: PageId::INVALID_PAGE_ID,
+            page_map_offset: 0,
+            key_check: [0; 64],
+            free_list_next: HEADER_PAGE_ID,
+            segment_pages: 0,
+            database_id: 0,
+            compact_staging: 0,
+            compact_end: 0,
+        }
+    }
+
+    // 先頭ページから読み込んだ領域からヘッダーを取り出す。領域はHEADER_AREA_SIZEの大きさでなければならない
+    pub fn decode(area: &[u8]) -> Self {
+        let mut header = Self::new(0);
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&area[..size_of::<Self>()]);
+        header
+    }
+
+    // ヘッダーを書き込む領域を作る。チェックサムもここで計算して埋める
+    pub fn encode(&self) -> Vec<u8> {
+        let mut area = vec![0u8; HEADER_AREA_SIZE];
+        area[..size_of::<Self>()].copy_from_slice(self.as_bytes());
+        let checksum = Self::compute_checksum(&area);
+        area[CHECKSUM_RANGE].copy_from_slice(&checksum.to_le_bytes());
+        area
+    }
+
+    // ヘッダーの領域から、チェックサムの部分を0として計算したチェックサム
+    pub fn compute_checksum(area: &[u8]) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&area[..CHECKSUM_RANGE.start]);
+        hasher.update(&[0; 4]);
+        hasher.update(&area[CHECKSUM_RANGE.end..]);
+        hasher.finalize()
+    }
+}
+
+// ヘッダーの中でチェックサムが置かれている位置
+const CHECKSUM_RANGE: Range<usize> = 20..24;
@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io;
+
+use memmap2::{Mmap, MmapOptions};
+
+use super::storage::{FileAt, Storage};
+
+/*
+    ファイルをメモリにマップし、読み込みをマップから直接行うストレージ。
+    読み込みのたびにシステムコールを呼ばずに済むので、ファイルがメモリに収まる読み込み中心の使い方に向いている。
+    書き込みはこれまでどおりファイルに書き、ファイルがマップより大きくなったときはマップを作り直す。
+    共有マップなので、書き込んだ内容はすぐにマップからも読める。
+
+    ページを追加するたびに作り直さないように、Unixではファイルの大きさを2の冪に切り上げた範囲までマップしておく。
+    ファイルの末尾より後ろは読まないように、ファイルの大きさは別に覚えておく。
+    Windowsではファイルより大きくマップするとファイルが広がってしまうので、ファイルの大きさだけマップする。
+
+    読み込みはマップからページデータのバッファへのコピーになる。
+    DiskManagerはチェックサムを確かめたり復号したりしてからフレームに置くので、マップを直接貸し出すことはしない。
+*/
+pub struct MmapStorage {
+    file: File,
+    // 空のファイルはマップできないので、そのときはNoneにしておく
+    map: Option<Mmap>,
+    // ファイルの大きさ。マップはこれより大きいことがある
+    len: u64,
+}
+
+// マップを作り直すときに、少なくともこの大きさはマップしておく
+#[cfg(unix)]
+const MIN_MAP_LEN: u64 = 1 << 20;
+
+impl MmapStorage {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        let mut storage = Self {
+            file,
+            map: None,
+            len,
+        };
+        storage.remap()?;
+        Ok(storage)
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.len > 0 {
+            /*
+                SAFETY: マップしている間にファイルが他から縮められると、読み込みでSIGBUSになる。
+                DiskManagerはファイルにロックをかけて開き、自分で縮めるときはマップを作り直す。
+                ファイルの末尾より後ろのマップは、lenで範囲を確かめて読まないようにしている。
+            */
+            self.map = Some(unsafe { MmapOptions::new().len(map_len(self.len)).map(&self.file)? });
+        }
+        Ok(())
+    }
+
+    // ファイルの大きさをlenに広げたことを記録し、マップに収まらなければ作り直す
+    fn grow(&mut self, len: u64) -> io::Result<()> {
+        self.len = len;
+        if len > self.mapped_len() {
+            self.remap()?;
+        }
+        Ok(())
+    }
+
+    fn mapped_len(&self) -> u64 {
+        self.map.as_ref().map_or(0, |map| map.len() as u64)
+    }
+}
+
+impl Storage for MmapStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let src = self
+            .map
+            .as_deref()
+            .filter(|_| offset + data.len() as u64 <= self.len)
+            .and_then(|map| map.get(start..start + data.len()))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+            })?;
+        data.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, offset)?;
+        let end = offset + data.len() as u64;
+        if end > self.len {
+            self.grow(end)?;
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: u64) -> io::Result<()> {
+        if size > self.len {
+            self.file.set_len(size)?;
+            self.grow(size)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        if size < self.len {
+            // 縮める前にマップを外しておかないと、切り詰めた範囲を読めてしまう
+            self.map = None;
+            self.file.set_len(size)?;
+            self.len = size;
+            self.remap()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+// ファイルの大きさがlenのときにマップする大きさ
+#[cfg(unix)]
+fn map_len(len: u64) -> usize {
+    len.max(MIN_MAP_LEN)
+        .checked_next_power_of_two()
+        .unwrap_or(len)
+        .try_into()
+        .unwrap_or(usize::MAX)
+}
+
+#[cfg(not(unix))]
+fn map_len(len: u64) -> usize {
+    len as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{DiskManager, DiskOptions};
+    use tempfile::{tempdir, tempfile};
+
+    #[test]
+    fn test_grow() {
+        let mut storage = MmapStorage::new(tempfile().unwrap()).unwrap();
+        storage.allocate(4096).unwrap();
+        let mapped_len = storage.mapped_len();
+        storage.write_at(4096, &[7; 100]).unwrap();
+        // マップに収まる間は作り直さない
+        assert_eq!(mapped_len, storage.mapped_len());
+        assert_eq!(4196, storage.size().unwrap());
+        let mut buf = [0u8; 100];
+        storage.read_at(4096, &mut buf).unwrap();
+        assert_eq!([7; 100], buf);
+        // マップされていても、ファイルの末尾より後ろは読めない
+        assert!(storage.read_at(4100, &mut buf).is_err());
+        storage.truncate(4096).unwrap();
+        assert!(storage.read_at(4096, &mut buf).is_err());
+    }
+
+    #[test]
+    fn test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db");
+        let options = DiskOptions {
+            mmap: true,
+            ..Default::default()
+        };
+        let mut disk = DiskManager::open_with(&path, options.clone()).unwrap();
+        let data_size = disk.data_size();
+        let page_ids: Vec<_> = (0..3u8)
+            .map(|i| {
+                let page_id = disk.allocate_page().unwrap();
+                disk.write_page_data(page_id, &vec![i + 1; data_size])
+                    .unwrap();
+                page_id
+            })
+            .collect();
+        // 書き込んだ内容はすぐにマップから読める
+        let mut buf = vec![0u8; data_size];
+        disk.read_page_data(page_ids[1], &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 2));
+        disk.sync().unwrap();
+        drop(disk);
+
+        let disk2 = DiskManager::open_with(&path, options).unwrap();
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            disk2.read_page_data(page_id, &mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == i as u8 + 1));
+        }
+    }
+}
@@ -1,5 +1,25 @@
+mod cipher;
+mod compact;
+mod compress;
 mod disk;
+mod double_write;
+// 障害を起こすStorageは、テストとfault-injectionフィーチャーを有効にしたときだけ使える
+#[cfg(any(test, feature = "fault-injection"))]
+mod fault;
+mod header;
+mod mmap;
 mod page;
+mod segment;
+mod storage;
 
-pub use crate::disk::disk::DiskManager;
-pub use crate::disk::page::{PageId, PAGE_SIZE};
+pub use crate::disk::cipher::{ChaCha20Poly1305Cipher, PageCipher};
+pub use crate::disk::compact::{CompactionStats, Relocation};
+pub use crate::disk::compress::CompressionStats;
+pub use crate::disk::disk::{DiskManager, DiskOptions, Error};
+pub use crate::disk::double_write::DoubleWriteStorage;
+#[cfg(any(test, feature = "fault-injection"))]
+pub use crate::disk::fault::{FaultConfig, FaultyStorage};
+pub use crate::disk::mmap::MmapStorage;
+pub use crate::disk::page::{is_valid_page_size, PageId, MAX_PAGE_SIZE, MIN_PAGE_SIZE, PAGE_SIZE};
+pub use crate::disk::segment::SegmentedStorage;
+pub use crate::disk::storage::{FileStorage, MemoryStorage, Storage};
@@ -3,8 +3,16 @@ use zerocopy::{AsBytes, FromBytes};
 /*
     ファイルサイズの単位。
     Linuxのext4のファイルサイズが4096のため、ページサイズはこの整数倍とされていることが多い。
+    ページサイズはデータベースを作るときに選ぶことができ、指定しなければこの値が使われる。
 */
 pub const PAGE_SIZE: usize = 4096;
+// 選ぶことのできるページサイズの範囲。この範囲の2のべき乗でなければならない
+pub const MIN_PAGE_SIZE: usize = 1024;
+pub const MAX_PAGE_SIZE: usize = 64 * 1024;
+
+pub fn is_valid_page_size(page_size: usize) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
 
 #[derive(Debug, Default, Eq, PartialEq, Hash, Copy, Clone, FromBytes, AsBytes)]
 #[repr(C)]
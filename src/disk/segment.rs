@@ -0,0 +1,310 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::storage::{FileAt, Storage};
+
+/*
+    ページを決まった大きさのセグメントファイルに分けて保存するストレージ。
+    base_pathが"db"であれば、先頭からsegment_sizeバイトずつ"db.0"、"db.1"、...に保存される。
+    オフセットからセグメントとセグメント内のオフセットへの変換はこのストレージの中で行うので、
+    DiskManagerからは一つの大きなファイルのように見える。セグメントは必要になったときに作られる。
+    先頭のセグメントには、書き込む場合は排他ロック、読み込み専用の場合は共有ロックをかける。
+*/
+pub struct SegmentedStorage {
+    base_path: PathBuf,
+    segment_size: u64,
+    segments: Vec<File>,
+    read_only: bool,
+}
+
+impl SegmentedStorage {
+    pub fn open(base_path: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        Self::open_with(base_path, segment_size, false)
+    }
+
+    // 読み込み専用で開く。セグメントは作られず、書き込みはエラーになる
+    pub fn open_read_only(base_path: impl AsRef<Path>, segment_size: u64) -> io::Result<Self> {
+        Self::open_with(base_path, segment_size, true)
+    }
+
+    fn open_with(
+        base_path: impl AsRef<Path>,
+        segment_size: u64,
+        read_only: bool,
+    ) -> io::Result<Self> {
+        let first = Self::open_first(base_path.as_ref(), read_only)?;
+        Self::with_first(base_path, first, segment_size, read_only)
+    }
+
+    /*
+        先頭のセグメントを開いてロックをかける。書き込む場合は、まだなければ作る。
+        セグメントの大きさを決めるためにヘッダーを読む前に、他のプロセスが書き換えられないようにしておく。
+    */
+    pub(crate) fn open_first(base_path: &Path, read_only: bool) -> io::Result<File> {
+        let path = segment_path(base_path, 0);
+        let first = match OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(&path)
+        {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} does not exist", path.display()),
+                ))
+            }
+            result => result?,
+        };
+        if read_only {
+            fs2::FileExt::try_lock_shared(&first)?;
+        } else {
+            fs2::FileExt::try_lock_exclusive(&first)?;
+        }
+        Ok(first)
+    }
+
+    // open_firstでロックをかけた先頭のセグメントに続けて、残りのセグメントを開く
+    pub(crate) fn with_first(
+        base_path: impl AsRef<Path>,
+        first: File,
+        segment_size: u64,
+        read_only: bool,
+    ) -> io::Result<Self> {
+        if segment_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "segment size must not be zero",
+            ));
+        }
+        let mut storage = Self {
+            base_path: base_path.as_ref().to_path_buf(),
+            segment_size,
+            segments: vec![first],
+            read_only,
+        };
+        // 既にあるセグメントを順に開く
+        loop {
+            let path = storage.segment_path(storage.segments.len());
+            match OpenOptions::new().read(true).write(!read_only).open(&path) {
+                Ok(file) => storage.segments.push(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+        // 最後のセグメント以外はすべてsegment_sizeの大きさになっているはず
+        for (index, segment) in storage.segments.iter().enumerate() {
+            let len = segment.metadata()?.len();
+            let is_last = index + 1 == storage.segments.len();
+            if len > segment_size || (!is_last && len != segment_size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} does not match the segment size of {} bytes",
+                        storage.segment_path(index).display(),
+                        segment_size
+                    ),
+                ));
+            }
+        }
+        Ok(storage)
+    }
+
+    pub fn segment_path(&self, index: usize) -> PathBuf {
+        segment_path(&self.base_path, index)
+    }
+
+    // index番目のセグメントを返す。まだなければ、手前のセグメントも含めて作る
+    fn segment(&mut self, index: usize) -> io::Result<&File> {
+        if self.read_only && self.segments.len() <= index {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "segmented storage is opened read-only",
+            ));
+        }
+        while self.segments.len() <= index {
+            // 後ろにセグメントを足す前に、それまでの最後のセグメントを埋めておく
+            if let Some(last) = self.segments.last() {
+                last.set_len(self.segment_size)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.segment_path(self.segments.len()))?;
+            self.segments.push(file);
+        }
+        Ok(&self.segments[index])
+    }
+
+    // offsetからlenバイトの範囲を、(セグメントの番号, セグメント内のオフセット, 範囲内の位置)に分割する
+    fn split(&self, offset: u64, len: usize) -> Vec<(usize, u64, usize, usize)> {
+        let mut pieces = vec![];
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let index = (position / self.segment_size) as usize;
+            let segment_offset = position % self.segment_size;
+            let n = ((self.segment_size - segment_offset) as usize).min(len - done);
+            pieces.push((index, segment_offset, done, done + n));
+            done += n;
+        }
+        pieces
+    }
+}
+
+pub fn segment_path(base_path: &Path, index: usize) -> PathBuf {
+    let mut path = base_path.as_os_str().to_owned();
+    path.push(format!(".{}", index));
+    PathBuf::from(path)
+}
+
+impl Storage for SegmentedStorage {
+    fn size(&self) -> io::Result<u64> {
+        match self.segments.last() {
+            Some(last) => {
+                Ok((self.segments.len() as u64 - 1) * self.segment_size + last.metadata()?.len())
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        for (index, segment_offset, start, end) in self.split(offset, data.len()) {
+            let segment = self.segments.get(index).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+            })?;
+            segment.read_exact_at(&mut data[start..end], segment_offset)?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        for (index, segment_offset, start, end) in self.split(offset, data.len()) {
+            self.segment(index)?
+                .write_all_at(&data[start..end], segment_offset)?;
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: u64) -> io::Result<()> {
+        if size <= self.size()? {
+            return Ok(());
+        }
+        let index = ((size - 1) / self.segment_size) as usize;
+        let len = size - index as u64 * self.segment_size;
+        let segment = self.segment(index)?;
+        if segment.metadata()?.len() < len {
+            segment.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    // sizeより後ろだけを含むセグメントは削除する。先頭のセグメントは常に残す
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        if size >= self.size()? {
+            return Ok(());
+        }
+        let keep = (size.div_ceil(self.segment_size) as usize).max(1);
+        while self.segments.len() > keep {
+            self.segments.pop();
+            std::fs::remove_file(self.segment_path(self.segments.len()))?;
+        }
+        let last = keep - 1;
+        self.segments[last].set_len(size - last as u64 * self.segment_size)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for segment in &self.segments {
+            segment.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{DiskManager, DiskOptions, PageId};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test() {
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("db");
+        let options = DiskOptions {
+            page_size: 1024,
+            ..Default::default()
+        };
+        let mut disk = DiskManager::open_segmented(&base_path, 2, options.clone()).unwrap();
+        let data_size = disk.data_size();
+        let page_ids: Vec<_> = (0..5u8)
+            .map(|i| {
+                let page_id = disk.allocate_page().unwrap();
+                disk.write_page_data(page_id, &vec![i; data_size]).unwrap();
+                page_id
+            })
+            .collect();
+        disk.sync().unwrap();
+        drop(disk);
+
+        // ヘッダーを含めて6ページあるので、2ページずつ3つのセグメントに分かれる
+        for index in 0..3 {
+            assert!(segment_path(&base_path, index).exists());
+        }
+        assert!(!segment_path(&base_path, 3).exists());
+
+        let disk2 = DiskManager::open_segmented(&base_path, 2, options).unwrap();
+        let mut buf = vec![0u8; data_size];
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            disk2.read_page_data(page_id, &mut buf).unwrap();
+            assert!(buf.iter().all(|&b| b == i as u8));
+        }
+        assert!(disk2.read_page_data(PageId(6), &mut buf).is_err());
+        drop(disk2);
+
+        // セグメントの大きさが違うと開けない
+        assert!(SegmentedStorage::open(&base_path, 4096).is_err());
+    }
+
+    #[test]
+    fn test_segment_pages() {
+        let dir = tempdir().unwrap();
+        let base_path = dir.path().join("db");
+        let mut disk = DiskManager::open_segmented(&base_path, 8, DiskOptions::default()).unwrap();
+        disk.allocate_page().unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+
+        // セグメントが一つしかなく大きさでは区別できなくても、ヘッダーに記録されたページ数と違えば開けない
+        assert!(DiskManager::open_segmented(&base_path, 16, DiskOptions::default()).is_err());
+        // セグメントに分けていないファイルとしても開けない
+        assert!(DiskManager::open(segment_path(&base_path, 0)).is_err());
+        DiskManager::open_segmented(&base_path, 8, DiskOptions::default()).unwrap();
+
+        // メモリマップはセグメントに分けたストレージでは使えない
+        let options = DiskOptions {
+            mmap: true,
+            ..Default::default()
+        };
+        assert!(DiskManager::open_segmented(&base_path, 8, options).is_err());
+    }
+
+    #[test]
+    fn test_read_write_across_segments() {
+        let dir = tempdir().unwrap();
+        let mut storage = SegmentedStorage::open(dir.path().join("db"), 10).unwrap();
+        let data: Vec<u8> = (0..25).collect();
+        storage.write_at(3, &data).unwrap();
+        assert_eq!(28, storage.size().unwrap());
+        let mut buf = vec![0u8; 25];
+        storage.read_at(3, &mut buf).unwrap();
+        assert_eq!(data, buf);
+        assert!(storage.read_at(20, &mut buf).is_err());
+        storage.allocate(45).unwrap();
+        assert_eq!(45, storage.size().unwrap());
+    }
+}
@@ -0,0 +1,200 @@
+use std::fs::File;
+use std::io;
+
+/*
+    DiskManagerがページを読み書きする先を抽象化したもの。
+    DiskManagerはページIDからオフセットを計算し、ページやヘッダーの読み書き、
+    新しいページの確保、永続化をこのトレイトを通して行う。
+    読み込みは位置を指定して行うので&selfで呼び出すことができ、複数の読み込みを同時に行える。
+    DiskManagerをスレッド間で受け渡し、複数のスレッドから同時に読み込めるように、SendかつSyncでなければならない。
+*/
+pub trait Storage: Send + Sync {
+    // ストレージの大きさ(バイト数)を返す
+    fn size(&self) -> io::Result<u64>;
+    // offsetの位置からdataの長さだけ読み込む。範囲がストレージの外にはみ出す場合はエラーになる
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()>;
+    // offsetの位置にdataを書き込む。必要に応じてストレージは広がる
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+    /*
+        writesの(オフセット, データ)を順に書き込む。
+        一つずつ書き込むより、まとめたほうが速いストレージはこれを上書きする。
+    */
+    fn write_batch(&mut self, writes: &[(u64, &[u8])]) -> io::Result<()> {
+        for &(offset, data) in writes {
+            self.write_at(offset, data)?;
+        }
+        Ok(())
+    }
+    // 新しいページのためにストレージをsizeバイトまで広げる。広げた部分は0で埋められる
+    fn allocate(&mut self, size: u64) -> io::Result<()>;
+    // ストレージをsizeバイトまで縮める。sizeより後ろの内容は失われる
+    fn truncate(&mut self, size: u64) -> io::Result<()>;
+    // 書き込んだ内容を永続化する
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/*
+    ファイルの位置を指定した読み書き。
+    Unixではpread/pwriteをそのまま使い、Windowsではseek_read/seek_writeを繰り返して全体を読み書きする。
+    Windowsではファイルの現在位置が動くが、ストレージは現在位置を使わないので問題ない。
+*/
+pub(crate) trait FileAt {
+    fn read_exact_at(&self, data: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+impl FileAt for File {
+    fn read_exact_at(&self, data: &mut [u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(self, data, offset)
+    }
+
+    fn write_all_at(&self, data: &[u8], offset: u64) -> io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(self, data, offset)
+    }
+}
+
+#[cfg(windows)]
+impl FileAt for File {
+    fn read_exact_at(&self, mut data: &mut [u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !data.is_empty() {
+            match self.seek_read(data, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "failed to fill whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    let rest = data;
+                    data = &mut rest[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+        use std::os::windows::fs::FileExt;
+        while !data.is_empty() {
+            match self.seek_write(data, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => {
+                    data = &data[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// ファイルにページを保存するストレージ
+pub struct FileStorage {
+    file: File,
+}
+
+impl FileStorage {
+    pub fn new(file: File) -> Self {
+        Self { file }
+    }
+}
+
+impl Storage for FileStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(data, offset)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(data, offset)
+    }
+
+    fn allocate(&mut self, size: u64) -> io::Result<()> {
+        if size > self.size()? {
+            self.file.set_len(size)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        if size < self.size()? {
+            self.file.set_len(size)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+/*
+    メモリ上のVecにページを保存するストレージ。
+    プロセスが終了すると内容は失われるため、テストや一時的なキャッシュに使う。
+*/
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let src = self.data.get(start..start + data.len()).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+        })?;
+        data.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let start = offset as usize;
+        let end = start + data.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: u64) -> io::Result<()> {
+        if size as usize > self.data.len() {
+            self.data.resize(size as usize, 0);
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> io::Result<()> {
+        self.data.truncate(size as usize);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
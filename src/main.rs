@@ -19,10 +19,11 @@ fn main() -> Result<()> {
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
     let mut table = SimpleTable {
-        meta_page_id: PageId(0),
+        meta_page_id: PageId::INVALID_PAGE_ID,
         num_key_elems: 1,
     };
     table.create(&mut bufmgr)?;
+    bufmgr.set_catalog_root(table.meta_page_id)?;
     dbg!(&table);
     table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"])?;
     table.insert(&mut bufmgr, &[b"x", b"Bob", b"Johnson"])?;